@@ -0,0 +1,122 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Path the web server serves cached art at, followed by the id.
+const URL_PREFIX: &str = "/api/art/";
+
+pub struct Art {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Keeps cover art of local files in memory so that it can be served by the
+/// web server instead of embedding it into every response as a data URI.
+#[derive(Clone)]
+pub struct ArtCache {
+    inner: Arc<Mutex<ArtCacheInner>>,
+    capacity: usize,
+}
+
+struct ArtCacheInner {
+    art: HashMap<String, Arc<Art>>,
+    /// Ids in insertion order, the oldest art is evicted first
+    order: VecDeque<String>,
+}
+
+impl ArtCache {
+    /// A capacity of 0 disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ArtCacheInner {
+                art: HashMap::new(),
+                order: VecDeque::new(),
+            })),
+            capacity,
+        }
+    }
+
+    /// Stores the art and returns the url it is served at,
+    /// `None` is returned if the cache is disabled.
+    pub fn insert(&self, mime_type: &str, data: &[u8]) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        // The same cover is shared by all tracks of an album
+        let id = format!("{:x}", md5::compute(data));
+
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.art.contains_key(&id) {
+            if inner.order.len() >= self.capacity {
+                if let Some(oldest) = inner.order.pop_front() {
+                    inner.art.remove(&oldest);
+                }
+            }
+
+            let art = Art {
+                mime_type: mime_type.to_owned(),
+                data: data.to_vec(),
+            };
+            inner.order.push_back(id.clone());
+            inner.art.insert(id.clone(), Arc::new(art));
+        }
+
+        Some(format!("{}{}", URL_PREFIX, id))
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Art>> {
+        self.inner.lock().unwrap().art.get(id).cloned()
+    }
+
+    /// Looks up art by the url returned from `insert`.
+    pub fn get_by_url(&self, url: &str) -> Option<Arc<Art>> {
+        self.get(url.strip_prefix(URL_PREFIX)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_art() {
+        let cache = ArtCache::new(2);
+        let first = cache.insert("image/png", b"first").unwrap();
+        let second = cache.insert("image/png", b"second").unwrap();
+        let third = cache.insert("image/jpeg", b"third").unwrap();
+
+        assert!(cache.get_by_url(&first).is_none());
+        assert_eq!(cache.get_by_url(&second).unwrap().data, b"second");
+        assert_eq!(cache.get_by_url(&third).unwrap().mime_type, "image/jpeg");
+    }
+
+    #[test]
+    fn same_art_is_stored_once() {
+        let cache = ArtCache::new(2);
+        let first = cache.insert("image/png", b"cover").unwrap();
+        let other = cache.insert("image/png", b"other").unwrap();
+        // Inserting the cover again does not evict anything
+        assert_eq!(cache.insert("image/png", b"cover").unwrap(), first);
+
+        assert!(cache.get_by_url(&first).is_some());
+        assert!(cache.get_by_url(&other).is_some());
+    }
+
+    #[test]
+    fn lookup_by_id_and_url() {
+        let cache = ArtCache::new(1);
+        let url = cache.insert("image/png", b"cover").unwrap();
+        let id = url.strip_prefix(URL_PREFIX).unwrap();
+
+        assert_eq!(id, format!("{:x}", md5::compute(b"cover")));
+        assert!(cache.get(id).is_some());
+        assert!(cache.get_by_url(id).is_none());
+        assert!(cache.get_by_url("/api/art/unknown").is_none());
+    }
+
+    #[test]
+    fn capacity_zero_disables_cache() {
+        let cache = ArtCache::new(0);
+        assert_eq!(cache.insert("image/png", b"cover"), None);
+    }
+}
@@ -1,4 +1,6 @@
-use std::sync::Once;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
 use std::time::Duration;
 
 use gst::prelude::*;
@@ -17,6 +19,22 @@ use crate::youtube_dl::AudioMetadata;
 
 static GST_INIT: Once = Once::new();
 
+/// Volume changes are spread over this time to avoid audible clicks
+const VOLUME_RAMP: Duration = Duration::from_millis(100);
+const VOLUME_RAMP_STEPS: u32 = 10;
+
+/// How often a track is restarted after errors before it is skipped
+const MAX_RECOVERY_ATTEMPTS: u32 = 3;
+
+/// How often the watchdog checks the position for the end offset
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a seek waits for a track that was just started to be ready
+const SEEK_READY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Highest quality setting of the audioresample element
+const RESAMPLE_QUALITY_MAX: i32 = 10;
+
 pub struct AudioPlayer {
     pipeline: gst::Pipeline,
     bus: gst::Bus,
@@ -24,11 +42,73 @@ pub struct AudioPlayer {
 
     volume_f64: f64,
     volume: gst::Element,
+    /// Generation of the running volume ramp, it is locked while setting the
+    /// volume so that an old ramp can not overwrite a newer volume
+    volume_ramp: Arc<Mutex<u64>>,
     currently_playing: Option<AudioMetadata>,
 
+    resume_after_error: bool,
+    recovery_attempts: Arc<AtomicU32>,
+    /// Incremented to stop the end offset watchdog of the previous track
+    watchdog_generation: Arc<AtomicU64>,
+
+    queue_max_time: Option<Duration>,
+    queue_max_bytes: Option<u32>,
+    resample_quality: Option<i32>,
+    /// Encode mono speech instead of stereo music
+    voice: bool,
+    /// Set while a network source is filling its buffer
+    buffering: Arc<AtomicBool>,
+
     logger: Logger,
 }
 
+/// Elements every player needs, `local` players play on the speakers
+/// instead of encoding for TeamSpeak.
+fn required_elements(local: bool) -> &'static [&'static str] {
+    if local {
+        &[
+            "uridecodebin",
+            "volume",
+            "queue",
+            "audioconvert",
+            "audioresample",
+            "autoaudiosink",
+        ]
+    } else {
+        &[
+            "uridecodebin",
+            "volume",
+            "queue",
+            "audioconvert",
+            "audioresample",
+            "opusenc",
+            "appsink",
+        ]
+    }
+}
+
+/// Name of the GStreamer package that contains an element.
+fn plugin_package(element: &str) -> &'static str {
+    match element {
+        "queue" => "gstreamer",
+        "autoaudiosink" => "gst-plugins-good",
+        _ => "gst-plugins-base",
+    }
+}
+
+/// Checks that all elements can be created before any bot starts, so a missing
+/// plugin is reported once instead of failing every connection.
+pub fn check_plugins(local: bool) -> Result<(), AudioPlayerError> {
+    GST_INIT.call_once(|| gst::init().unwrap());
+
+    for name in required_elements(local) {
+        make_element(name, name)?;
+    }
+
+    Ok(())
+}
+
 fn make_element(factoryname: &str, display_name: &str) -> Result<gst::Element, AudioPlayerError> {
     Ok(gst::ElementFactory::make(factoryname, Some(display_name))
         .map_err(|_| AudioPlayerError::MissingPlugin(factoryname.to_string()))?)
@@ -92,7 +172,16 @@ impl AudioPlayer {
             logger,
             volume_f64: 0.0,
             volume,
+            volume_ramp: Arc::new(Mutex::new(0)),
             currently_playing: None,
+            resume_after_error: false,
+            recovery_attempts: Arc::new(AtomicU32::new(0)),
+            watchdog_generation: Arc::new(AtomicU64::new(0)),
+            queue_max_time: None,
+            queue_max_bytes: None,
+            resample_quality: None,
+            voice: false,
+            buffering: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -104,8 +193,17 @@ impl AudioPlayer {
 
         let audio_bin = gst::Bin::new(Some("audio bin"));
         let queue = make_element("queue", "audio queue")?;
+        if let Some(max_time) = self.queue_max_time {
+            queue.set_property("max-size-time", &(max_time.as_nanos() as u64))?;
+        }
+        if let Some(max_bytes) = self.queue_max_bytes {
+            queue.set_property("max-size-bytes", &max_bytes)?;
+        }
         let convert = make_element("audioconvert", "audio converter")?;
         let resample = make_element("audioresample", "audio resampler")?;
+        if let Some(quality) = self.resample_quality {
+            resample.set_property("quality", &quality)?;
+        }
         let pads = queue.sink_pads();
         let queue_sink_pad = pads.first().unwrap();
 
@@ -113,6 +211,12 @@ impl AudioPlayer {
 
         if let Some(mut callback) = callback {
             let opus_enc = make_element("opusenc", "opus encoder")?;
+            let channels = if self.voice {
+                opus_enc.set_property_from_str("audio-type", "voice")?;
+                1i32
+            } else {
+                2i32
+            };
             let sink = make_element("appsink", "app sink")?;
 
             let appsink = sink
@@ -121,7 +225,7 @@ impl AudioPlayer {
                 .expect("Sink element is expected to be an appsink!");
             appsink.set_caps(Some(&gst::Caps::new_simple(
                 "audio/x-opus",
-                &[("channels", &(2i32)), ("rate", &(48_000i32))],
+                &[("channels", &channels), ("rate", &(48_000i32))],
             )));
             let callbacks = AppSinkCallbacks::builder()
                 .new_sample(move |sink| {
@@ -171,13 +275,118 @@ impl AudioPlayer {
         Ok(())
     }
 
+    /// Limits of the buffer in front of the encoder, GStreamer defaults are
+    /// used for limits that are not set. Has to be called before the setup.
+    pub fn set_queue_size(&mut self, max_time: Option<Duration>, max_bytes: Option<u32>) {
+        self.queue_max_time = max_time;
+        self.queue_max_bytes = max_bytes;
+    }
+
+    /// Quality of the audioresample element from 0 to 10, higher values need
+    /// more CPU. Has to be called before the setup.
+    pub fn set_resample_quality(&mut self, quality: Option<i32>) -> Result<(), AudioPlayerError> {
+        if let Some(quality) = quality {
+            if !(0..=RESAMPLE_QUALITY_MAX).contains(&quality) {
+                return Err(AudioPlayerError::InvalidResampleQuality(quality));
+            }
+        }
+
+        self.resample_quality = quality;
+        Ok(())
+    }
+
+    /// Encodes for the Opus Voice codec of TeamSpeak instead of Opus Music.
+    /// Has to be called before the setup.
+    pub fn set_voice(&mut self, voice: bool) {
+        self.voice = voice;
+    }
+
+    /// Restart local files at their last position if playback fails,
+    /// instead of skipping to the next track.
+    pub fn set_resume_after_error(&mut self, enabled: bool) {
+        self.resume_after_error = enabled;
+    }
+
     pub fn set_metadata(&mut self, data: AudioMetadata) -> Result<(), AudioPlayerError> {
+        self.recovery_attempts.store(0, Ordering::SeqCst);
         self.set_source_uri(data.uri.clone())?;
+        self.start_watchdog(data.start_offset, data.end_offset);
         self.currently_playing = Some(data);
 
         Ok(())
     }
 
+    /// Stops a running volume ramp, the volume stays where the ramp was.
+    fn cancel_volume_ramp(&self) {
+        *self.volume_ramp.lock().unwrap() += 1;
+    }
+
+    fn cancel_watchdog(&self) {
+        self.watchdog_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Seeks to `start` once playback started and ends the stream when the
+    /// position reaches `end`. Only one watchdog runs at a time, starting a
+    /// new one stops the previous one.
+    fn start_watchdog(&self, start: Option<Duration>, end: Option<Duration>) {
+        let generation = self.watchdog_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        if start.is_none() && end.is_none() {
+            return;
+        }
+
+        let watchdog_generation = self.watchdog_generation.clone();
+        let pipeline = self.pipeline.clone();
+        let bus = self.bus.clone();
+        let logger = self.logger.clone();
+        thread::spawn(move || {
+            let is_current = || watchdog_generation.load(Ordering::SeqCst) == generation;
+
+            // Seeking only works once the pipeline has prerolled
+            loop {
+                if !is_current() {
+                    return;
+                }
+
+                let (_, current, _) = pipeline.state(gst::ClockTime::from_mseconds(100));
+                if let gst::State::Paused | gst::State::Playing = current {
+                    break;
+                }
+                thread::sleep(WATCHDOG_INTERVAL);
+            }
+
+            if let Some(start) = start {
+                info!(
+                    logger,
+                    "Seeking to start of clip";
+                    "time" => %humantime::format_duration(start)
+                );
+                let position = gst::ClockTime::from_nseconds(start.as_nanos() as _);
+                if let Err(e) = pipeline.seek_simple(gst::SeekFlags::FLUSH, position) {
+                    warn!(logger, "Failed to seek to start of clip"; "error" => %e);
+                }
+            }
+
+            let end = match end {
+                Some(end) => end,
+                None => return,
+            };
+            while is_current() {
+                let position = pipeline
+                    .query_position::<gst::ClockTime>()
+                    .map(|t| Duration::from_nanos(t.nseconds()));
+                if position.map_or(false, |position| position >= end) {
+                    info!(logger, "Reached end offset");
+                    if let Err(e) = bus.post(&gst::message::Eos::new()) {
+                        error!(logger, "Failed to end track at end offset"; "error" => %e);
+                    }
+                    return;
+                }
+
+                thread::sleep(WATCHDOG_INTERVAL);
+            }
+        });
+    }
+
     fn set_source_uri(&self, location: String) -> Result<(), AudioPlayerError> {
         info!(self.logger, "Setting source"; "uri" => &location);
         self.uri_src.set_property("uri", &location)?;
@@ -190,6 +399,8 @@ impl AudioPlayer {
             VolumeChange::Positive(vol) => self.volume_f64 + vol,
             VolumeChange::Negative(vol) => self.volume_f64 - vol,
             VolumeChange::Absolute(vol) => vol,
+            // The player does not know the default, the bot resolves resets
+            VolumeChange::Reset => self.volume_f64,
         };
         let new_volume = new_volume.max(0.0).min(1.0);
 
@@ -200,7 +411,45 @@ impl AudioPlayer {
         let linear =
             StreamVolume::convert_volume(StreamVolumeFormat::Db, StreamVolumeFormat::Linear, db);
 
-        self.volume.set_property("volume", &linear)?;
+        // Cancels a ramp that might still be running
+        let mut current = self.volume_ramp.lock().unwrap();
+        *current += 1;
+        let generation = *current;
+
+        let start = self
+            .volume
+            .property("volume")
+            .ok()
+            .and_then(|v| v.get::<f64>().ok());
+        let start = match start {
+            Some(start) if self.pipeline.current_state() == gst::State::Playing => start,
+            _ => {
+                self.volume.set_property("volume", &linear)?;
+                return Ok(());
+            }
+        };
+
+        drop(current);
+
+        let ramp = self.volume_ramp.clone();
+        let volume = self.volume.clone();
+        let logger = self.logger.clone();
+        thread::spawn(move || {
+            for step in 1..=VOLUME_RAMP_STEPS {
+                thread::sleep(VOLUME_RAMP / VOLUME_RAMP_STEPS);
+                let current = ramp.lock().unwrap();
+                if *current != generation {
+                    return;
+                }
+
+                let progress = f64::from(step) / f64::from(VOLUME_RAMP_STEPS);
+                let value = start + (linear - start) * progress;
+                if let Err(e) = volume.set_property("volume", &value) {
+                    warn!(logger, "Failed to ramp volume"; "error" => %e);
+                    return;
+                }
+            }
+        });
 
         Ok(())
     }
@@ -209,6 +458,9 @@ impl AudioPlayer {
         info!(self.logger, "Setting pipeline state"; "to" => "null");
 
         self.currently_playing = None;
+        self.buffering.store(false, Ordering::SeqCst);
+        self.cancel_watchdog();
+        self.cancel_volume_ramp();
 
         self.pipeline.set_state(gst::State::Null)?;
 
@@ -232,29 +484,18 @@ impl AudioPlayer {
     }
 
     pub fn seek(&self, seek: Seek) -> Result<humantime::FormattedDuration, AudioPlayerError> {
-        let base = match seek {
-            Seek::Positive(_) | Seek::Negative(_) => {
-                let pos = self
-                    .pipeline
-                    .query_position::<gst::ClockTime>()
-                    .ok_or(AudioPlayerError::SeekError)?;
+        self.wait_until_prerolled()?;
 
-                Duration::from_nanos(pos.nseconds())
-            }
-            _ => Duration::new(0, 0),
-        };
+        let position = self
+            .pipeline
+            .query_position::<gst::ClockTime>()
+            .map(|pos| Duration::from_nanos(pos.nseconds()));
+        let duration = self
+            .pipeline
+            .query_duration::<gst::ClockTime>()
+            .map(|dur| Duration::from_nanos(dur.nseconds()));
 
-        let absolute = match seek {
-            Seek::Positive(duration) => base + duration,
-            Seek::Negative(duration) => {
-                if duration > base {
-                    Duration::new(0, 0)
-                } else {
-                    base - duration
-                }
-            }
-            Seek::Absolute(duration) => duration,
-        };
+        let absolute = seek_target(seek, position, duration)?;
 
         let time = humantime::format_duration(absolute);
         info!(self.logger, "Seeking"; "time" => %time);
@@ -267,8 +508,24 @@ impl AudioPlayer {
         Ok(time)
     }
 
+    /// Seeks are ignored until the pipeline has prerolled, which takes a
+    /// moment after a track was started.
+    fn wait_until_prerolled(&self) -> Result<(), AudioPlayerError> {
+        let timeout = gst::ClockTime::from_nseconds(SEEK_READY_TIMEOUT.as_nanos() as _);
+        let (_, current, _) = self.pipeline.state(timeout);
+
+        match current {
+            gst::State::Paused | gst::State::Playing => Ok(()),
+            _ => {
+                warn!(self.logger, "Pipeline was not ready to seek"; "state" => ?current);
+                Err(AudioPlayerError::SeekError)
+            }
+        }
+    }
+
     pub fn stop_current(&self) -> Result<(), AudioPlayerError> {
         info!(self.logger, "Stopping pipeline, sending EOS");
+        self.cancel_watchdog();
 
         self.bus.post(&gst::message::Eos::new())?;
 
@@ -290,6 +547,18 @@ impl AudioPlayer {
         self.volume_f64
     }
 
+    /// Only true once the pipeline reached the playing state, not while it is
+    /// still changing to it.
+    pub fn is_playing(&self) -> bool {
+        let (_, current, pending) = self.pipeline.state(gst::ClockTime::ZERO);
+
+        current == gst::State::Playing && pending == gst::State::VoidPending
+    }
+
+    pub fn is_buffering(&self) -> bool {
+        self.buffering.load(Ordering::SeqCst)
+    }
+
     pub fn position(&self) -> Option<Duration> {
         self.pipeline
             .query_position::<gst::ClockTime>()
@@ -306,6 +575,11 @@ impl AudioPlayer {
 
         let logger = self.logger.clone();
         let handle = tokio::runtime::Handle::current();
+        let pipeline = self.pipeline.clone();
+        let uri_src = self.uri_src.clone();
+        let resume_after_error = self.resume_after_error;
+        let recovery_attempts = self.recovery_attempts.clone();
+        let buffering = self.buffering.clone();
         self.bus.set_sync_handler(move |_, msg| {
             use gst::MessageView;
 
@@ -345,6 +619,9 @@ impl AudioPlayer {
                         }
                     }
                 }
+                MessageView::Buffering(b) => {
+                    buffering.store(b.percent() < 100, Ordering::SeqCst);
+                }
                 MessageView::Eos(..) => {
                     info!(logger, "End of stream reached");
 
@@ -368,7 +645,28 @@ impl AudioPlayer {
                         "debug" => err.debug()
                     );
 
-                    send_state(&handle, &bot, State::EndOfStream);
+                    let is_local = uri_src
+                        .property("uri")
+                        .ok()
+                        .and_then(|uri| uri.get::<String>().ok())
+                        .map_or(false, |uri| uri.starts_with("file://"));
+                    // Broken files would be restarted forever without a limit
+                    if resume_after_error
+                        && is_local
+                        && recovery_attempts.fetch_add(1, Ordering::SeqCst) < MAX_RECOVERY_ATTEMPTS
+                    {
+                        let position = pipeline.query_position::<gst::ClockTime>();
+                        let pipeline = pipeline.clone();
+                        let logger = logger.clone();
+                        // State changes must not happen on the streaming thread
+                        handle.spawn_blocking(move || {
+                            if let Err(e) = restart_at(&pipeline, position, &logger) {
+                                error!(logger, "Failed to restart after error"; "error" => %e);
+                            }
+                        });
+                    } else {
+                        send_state(&handle, &bot, State::EndOfStream);
+                    }
                 }
                 _ => {
                     //debug!("Unhandled message on bus: {:?}", msg)
@@ -384,22 +682,60 @@ fn send_state(handle: &tokio::runtime::Handle, addr: &WeakAddress<MusicBot>, sta
     handle.spawn(addr.send(MusicBotMessage::StateChange(state)));
 }
 
+/// Restarts the pipeline and seeks to `position` once it is ready again.
+fn restart_at(
+    pipeline: &gst::Pipeline,
+    position: Option<gst::ClockTime>,
+    logger: &Logger,
+) -> Result<(), AudioPlayerError> {
+    info!(logger, "Restarting playback after error"; "position" => ?position);
+
+    // Going to Ready instead of Null avoids reporting the track as stopped
+    pipeline.set_state(gst::State::Ready)?;
+    pipeline.set_state(gst::State::Paused)?;
+
+    let (res, _, _) = pipeline.state(gst::ClockTime::from_seconds(10));
+    res?;
+
+    if let Some(position) = position {
+        pipeline.seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT, position)?;
+    }
+
+    pipeline.set_state(gst::State::Playing)?;
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum AudioPlayerError {
     MissingPlugin(String),
     GStreamerError(glib::error::BoolError),
     StateChangeFailed,
     SeekError,
+    UnknownDuration,
+    InvalidResampleQuality(i32),
 }
 
 impl std::fmt::Display for AudioPlayerError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         use AudioPlayerError::*;
         match self {
-            MissingPlugin(name) => write!(f, "The '{}' GStreamer plugin was not found", name),
+            MissingPlugin(name) => write!(
+                f,
+                "The '{}' GStreamer plugin was not found, install {} \
+                 (called gstreamer1.0-plugins-base, -good etc. on Debian)",
+                name,
+                plugin_package(name)
+            ),
             GStreamerError(e) => write!(f, "{}", e),
             StateChangeFailed => write!(f, "AudioPlayer failed to change state"),
             SeekError => write!(f, "AudioPlayer failed to seek"),
+            UnknownDuration => write!(f, "The duration of the track is unknown"),
+            InvalidResampleQuality(quality) => write!(
+                f,
+                "The resample quality has to be between 0 and {}, got {}",
+                RESAMPLE_QUALITY_MAX, quality
+            ),
         }
     }
 }
@@ -417,3 +753,89 @@ impl From<gst::StateChangeError> for AudioPlayerError {
         AudioPlayerError::StateChangeFailed
     }
 }
+
+/// Turns a seek into the absolute position to seek to.
+///
+/// Seeking relative to the current position needs `position`,
+/// percentages need `duration`.
+fn seek_target(
+    seek: Seek,
+    position: Option<Duration>,
+    duration: Option<Duration>,
+) -> Result<Duration, AudioPlayerError> {
+    let position = || position.ok_or(AudioPlayerError::SeekError);
+    // Seeking further than the whole track is the same as seeking to its end
+    let percent_of_track = |fraction: f64| {
+        duration
+            .map(|duration| duration.mul_f64(fraction.min(1.0)))
+            .ok_or(AudioPlayerError::UnknownDuration)
+    };
+
+    let target = match seek {
+        Seek::Absolute(amount) => amount,
+        Seek::Positive(amount) => position()? + amount,
+        Seek::Negative(amount) => position()?.checked_sub(amount).unwrap_or_default(),
+        Seek::AbsolutePercent(fraction) => percent_of_track(fraction)?,
+        Seek::PositivePercent(fraction) => {
+            let amount = percent_of_track(fraction)?;
+            position()? + amount
+        }
+        Seek::NegativePercent(fraction) => {
+            let amount = percent_of_track(fraction)?;
+            position()?.checked_sub(amount).unwrap_or_default()
+        }
+    };
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{required_elements, seek_target, AudioPlayerError};
+    use crate::command::Seek;
+
+    const POSITION: Option<Duration> = Some(Duration::from_secs(60));
+    const DURATION: Option<Duration> = Some(Duration::from_secs(200));
+
+    fn target(seek: &str) -> Result<Duration, AudioPlayerError> {
+        seek_target(seek.parse().unwrap(), POSITION, DURATION)
+    }
+
+    #[test]
+    fn seek_by_time() {
+        assert_eq!(target("1m30s").unwrap(), Duration::from_secs(90));
+        assert_eq!(target("+10s").unwrap(), Duration::from_secs(70));
+        assert_eq!(target("-2m").unwrap(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn seek_by_percent() {
+        assert_eq!(target("50%").unwrap(), Duration::from_secs(100));
+        assert_eq!(target("+10%").unwrap(), Duration::from_secs(80));
+        assert_eq!(target("-10%").unwrap(), Duration::from_secs(40));
+        assert_eq!(target("-50%").unwrap(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn percent_needs_duration() {
+        let seek = Seek::PositivePercent(0.1);
+
+        assert!(matches!(
+            seek_target(seek, POSITION, None),
+            Err(AudioPlayerError::UnknownDuration)
+        ));
+        assert!(seek_target(Seek::Positive(Duration::from_secs(1)), POSITION, None).is_ok());
+    }
+
+    #[test]
+    fn missing_plugin_names_package() {
+        let err = AudioPlayerError::MissingPlugin(String::from("opusenc"));
+        assert!(err.to_string().contains("gst-plugins-base"));
+
+        let err = AudioPlayerError::MissingPlugin(String::from("autoaudiosink"));
+        assert!(err.to_string().contains("gst-plugins-good"));
+        assert!(required_elements(false).contains(&"opusenc"));
+    }
+}
@@ -1,5 +1,9 @@
+mod blocklist;
+mod bulk;
+mod favorites;
 mod master;
 mod music;
 
+pub use blocklist::Blocklist;
 pub use master::*;
 pub use music::*;
@@ -0,0 +1,92 @@
+use crate::youtube_dl::{host_of, AudioMetadata};
+
+/// Domains and title keywords of tracks that may not be added.
+///
+/// Entries with a dot and without spaces are domains which also block their
+/// subdomains, all other entries block tracks with the text in their title.
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    domains: Vec<String>,
+    keywords: Vec<String>,
+}
+
+impl Blocklist {
+    pub fn new(entries: &[String]) -> Self {
+        let mut blocklist = Self::default();
+        for entry in entries {
+            let entry = entry.trim().to_lowercase();
+            if entry.is_empty() {
+                continue;
+            }
+
+            if entry.contains('.') && !entry.contains(char::is_whitespace) {
+                blocklist.domains.push(entry);
+            } else {
+                blocklist.keywords.push(entry);
+            }
+        }
+
+        blocklist
+    }
+
+    /// Returns the entry that blocks the track, if any.
+    pub fn blocked_by(&self, track: &AudioMetadata) -> Option<&str> {
+        let hosts = std::iter::once(&track.uri)
+            .chain(track.webpage_url.as_ref())
+            .filter_map(|url| host_of(url));
+        for host in hosts {
+            let domain = self.domains.iter().find(|domain| {
+                host == **domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .map_or(false, |sub| sub.ends_with('.'))
+            });
+            if let Some(domain) = domain {
+                return Some(domain);
+            }
+        }
+
+        let title = track.title.to_lowercase();
+        self.keywords
+            .iter()
+            .find(|keyword| title.contains(keyword.as_str()))
+            .map(|keyword| keyword.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Blocklist;
+    use crate::youtube_dl::AudioMetadata;
+
+    fn track(url: &str, title: &str) -> AudioMetadata {
+        AudioMetadata {
+            uri: String::from("https://cdn.example.net/stream"),
+            webpage_url: Some(url.to_owned()),
+            title: title.to_owned(),
+            thumbnail: None,
+            duration: None,
+            added_by: String::new(),
+            start_offset: None,
+            end_offset: None,
+        }
+    }
+
+    #[test]
+    fn blocked_by() {
+        let entries: Vec<_> = vec!["Example.com", "live stream", " "]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let blocklist = Blocklist::new(&entries);
+
+        let blocked = track("https://www.example.com/watch", "Song");
+        assert_eq!(blocklist.blocked_by(&blocked), Some("example.com"));
+        let blocked = track("https://youtube.com/watch", "24/7 LIVE STREAM");
+        assert_eq!(blocklist.blocked_by(&blocked), Some("live stream"));
+
+        let allowed = track("https://notexample.com/watch", "Song");
+        assert_eq!(blocklist.blocked_by(&allowed), None);
+        assert_eq!(Blocklist::new(&[]).blocked_by(&allowed), None);
+    }
+}
@@ -0,0 +1,72 @@
+/// Number of failure reasons listed in a summary, the rest are only counted.
+const MAX_LISTED_FAILURES: usize = 3;
+
+/// Collects the outcome of every item of a bulk operation so that a single
+/// summary can be sent instead of one message per item.
+#[derive(Debug, Default)]
+pub struct BulkResults {
+    queued: usize,
+    failures: Vec<String>,
+}
+
+impl BulkResults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn queued(&mut self, count: usize) {
+        self.queued += count;
+    }
+
+    /// Records a failed item, only the first line of the reason is kept.
+    pub fn failed(&mut self, reason: impl ToString) {
+        let reason = reason.to_string();
+        let reason = reason.lines().next().unwrap_or_default().trim().to_owned();
+        self.failures.push(reason);
+    }
+
+    pub fn summary(&self) -> String {
+        let total = self.queued + self.failures.len();
+        if self.failures.is_empty() {
+            return format!("Queued {}/{}", self.queued, total);
+        }
+
+        let mut reasons = self.failures[..self.failures.len().min(MAX_LISTED_FAILURES)].join("; ");
+        if self.failures.len() > MAX_LISTED_FAILURES {
+            reasons.push_str("; ...");
+        }
+
+        format!(
+            "Queued {}/{}, failed: {} ({})",
+            self.queued,
+            total,
+            self.failures.len(),
+            reasons
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BulkResults;
+
+    #[test]
+    fn summary() {
+        let mut results = BulkResults::new();
+        results.queued(8);
+        assert_eq!(results.summary(), "Queued 8/8");
+
+        results.failed("Private video\nSign in if you've been granted access");
+        results.failed("HTTP Error 404: Not Found");
+        assert_eq!(
+            results.summary(),
+            "Queued 8/10, failed: 2 (Private video; HTTP Error 404: Not Found)"
+        );
+
+        results.failed("a");
+        results.failed("b");
+        assert!(results
+            .summary()
+            .ends_with("failed: 4 (Private video; HTTP Error 404: Not Found; a; ...)"));
+    }
+}
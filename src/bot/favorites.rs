@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Favorite {
+    pub title: String,
+    /// Link to the page of the track or the path of a local file
+    pub url: String,
+}
+
+/// Keeps a list of favorite tracks per user, each one in its own file named
+/// after the key from `user_key`.
+pub struct Favorites {
+    dir: PathBuf,
+}
+
+impl Favorites {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, user: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", file_name(user)))
+    }
+
+    pub fn load(&self, user: &str) -> anyhow::Result<Vec<Favorite>> {
+        let data = match std::fs::read(self.path(user)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Appends a favorite and returns its number, a track that already is a
+    /// favorite keeps its number.
+    pub fn add(&self, user: &str, favorite: Favorite) -> anyhow::Result<usize> {
+        let mut favorites = self.load(user)?;
+        if let Some(index) = favorites.iter().position(|f| f.url == favorite.url) {
+            return Ok(index + 1);
+        }
+
+        favorites.push(favorite);
+        std::fs::write(self.path(user), serde_json::to_vec(&favorites)?)?;
+
+        Ok(favorites.len())
+    }
+}
+
+/// Unique identifiers are base64 which can contain a slash, the URL safe
+/// alphabet is used for the file name instead. Other characters like the `#`
+/// of keys without an identifier are replaced.
+fn file_name(user: &str) -> String {
+    user.chars()
+        .map(|c| match c {
+            '/' => '_',
+            '+' => '-',
+            c if c.is_ascii_alphanumeric() || c == '=' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_name, Favorite, Favorites};
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("ab+c/d="), "ab-c_d=");
+        assert_eq!(file_name("../x"), "___x");
+    }
+
+    #[test]
+    fn add_and_load() {
+        let dir = std::env::temp_dir().join(format!("pokebot-favorites-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let favorites = Favorites::new(dir.clone());
+        let favorite = |n: u8| Favorite {
+            title: format!("Song {}", n),
+            url: format!("https://example.com/{}", n),
+        };
+
+        assert!(favorites.load("a/b=").unwrap().is_empty());
+        assert_eq!(favorites.add("a/b=", favorite(1)).unwrap(), 1);
+        assert_eq!(favorites.add("a/b=", favorite(2)).unwrap(), 2);
+        assert_eq!(favorites.add("a/b=", favorite(1)).unwrap(), 1);
+        assert_eq!(
+            favorites.load("a/b=").unwrap(),
+            vec![favorite(1), favorite(2)]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,19 +1,49 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use futures::future;
 use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
-use serde::{Deserialize, Serialize};
-use slog::{error, info, o, trace, Logger};
-use tsclientlib::{ClientId, ConnectOptions, Connection, Identity, MessageTarget};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use slog::{debug, error, info, o, trace, warn, Logger};
+use tokio::sync::watch;
+use tsclientlib::{
+    ChannelId, ClientId, ConnectOptions, Connection, Identity, Invoker, MessageTarget, Version,
+};
+use tsproto_packets::packets::CodecType;
 use xtra::{spawn::Tokio, Actor, Address, Context, Handler, Message, WeakAddress};
 
+use crate::art_cache::{Art, ArtCache};
+use crate::log_bridge;
 use crate::teamspeak::TeamSpeakConnection;
 
 use crate::Args;
 
-use crate::bot::{GetBotData, GetChannel, GetName, MusicBot, MusicBotArgs, MusicBotMessage};
+use crate::bot::{
+    saved_queue_path, write_tracks, AddAudio, AppendPlaylist, AudioLocation, Blocklist,
+    ExecuteCommand, GetBotData, GetChannel, GetChannelPath, GetIdentity, GetName, MusicBot,
+    MusicBotArgs, MusicBotMessage, ReorderPlaylist, SetPlaylist, SetVolume, Shutdown, StateLabels,
+    SubscribeVolume, TakePlaylist,
+};
+use crate::command::Command;
+use crate::playlist::ReorderError;
+use crate::web_server::RecentTrack;
+use crate::youtube_dl::AudioMetadata;
+
+/// How many tracks are kept for the list of recently played tracks
+const MAX_RECENT_TRACKS: usize = 50;
+
+/// How long to wait for the clients of newly subscribed channels to arrive
+const SUBSCRIBE_WAIT: Duration = Duration::from_millis(500);
+
+/// Upper limit for the doubled wait between connection attempts
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Bots connect inside a handler of the master which can not answer pokes or
+/// web requests in the meantime, so they give up sooner than the master
+const BOT_CONNECT_ATTEMPTS: u32 = 2;
 
 pub struct MasterBot {
     config: MasterConfig,
@@ -22,6 +52,10 @@ pub struct MasterBot {
     available_names: Vec<String>,
     available_ids: Vec<Identity>,
     connected_bots: HashMap<String, Address<MusicBot>>,
+    /// Plays in the channel of the master if `master_plays` is enabled
+    player: Option<Address<MusicBot>>,
+    /// Tracks started by any bot, the newest one is at the front
+    recent_tracks: VecDeque<RecentTrack>,
     rng: SmallRng,
     logger: Logger,
 }
@@ -39,67 +73,195 @@ pub struct MasterArgs {
     pub domain: String,
     pub bind_address: String,
     pub webserver_enable: bool,
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    #[serde(default)]
+    pub upload_dir: Option<PathBuf>,
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: usize,
+    #[serde(default)]
+    pub admins: Vec<String>,
+    #[serde(default = "default_leave_warning")]
+    pub leave_warning: u64,
+    #[serde(default)]
+    pub verbose_feedback: bool,
+    #[serde(default)]
+    pub avatar_from_thumbnail: bool,
+    #[serde(default = "default_master_description")]
+    pub master_description: String,
+    #[serde(default = "default_description_interval")]
+    pub description_interval: u64,
+    pub playlist_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub private_errors: bool,
+    #[serde(default = "default_playing_label")]
+    pub playing_label: String,
+    #[serde(default = "default_paused_label")]
+    pub paused_label: String,
+    #[serde(default = "default_stopped_label")]
+    pub stopped_label: String,
+    #[serde(default)]
+    pub search_on_url_failure: bool,
+    #[serde(default)]
+    pub domain_cooldown: u64,
+    #[serde(default)]
+    pub resume_after_error: bool,
+    #[serde(default = "default_art_cache_size")]
+    pub art_cache_size: usize,
+    #[serde(default)]
+    pub master_plays: bool,
+    #[serde(default)]
+    pub client_version: Option<String>,
+    #[serde(default)]
+    pub queue_max_time: Option<u64>,
+    #[serde(default)]
+    pub queue_max_bytes: Option<u32>,
+    #[serde(default)]
+    pub expand_playlists: bool,
+    #[serde(default)]
+    pub announce_next: u64,
+    #[serde(default = "default_show_volume_in_name")]
+    pub show_volume_in_name: bool,
+    #[serde(default)]
+    pub master_channel_message: Option<String>,
+    #[serde(default)]
+    pub resample_quality: Option<i32>,
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(default = "default_stall_timeout")]
+    pub stall_timeout: u64,
+    #[serde(default)]
+    pub favorites_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub playlist_max_len: Option<usize>,
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub blocklist: Vec<String>,
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+    #[serde(default = "default_connect_backoff")]
+    pub connect_backoff: u64,
     pub names: Vec<String>,
     pub id: Option<Identity>,
     pub ids: Option<Vec<Identity>>,
 }
 
 impl MasterBot {
-    pub async fn spawn(args: MasterArgs, logger: Logger) -> Address<Self> {
+    pub async fn spawn(args: MasterArgs, logger: Logger) -> anyhow::Result<Address<Self>> {
         info!(logger, "Starting in TeamSpeak mode");
 
-        let mut con_config = Connection::build(args.address.clone())
-            .version(tsclientlib::Version::Linux_3_3_2)
-            .name(args.master_name.clone())
-            .identity(args.id.expect("identity should exist"))
-            .log_commands(args.verbose >= 1)
-            .log_packets(args.verbose >= 2)
-            .log_udp_packets(args.verbose >= 3);
+        let identity = args.id.clone().expect("identity should exist");
+        let address = args.address.clone();
+        let master_name = args.master_name.clone();
+        let channel = args.channel.clone();
+        let verbose = args.verbose;
 
-        if let Some(channel) = args.channel {
-            con_config = con_config.channel(channel);
-        }
+        let master = Self::new(args, logger.clone()).await?;
+        let retry = master.config.retry;
+        let version = master.config.client_version.clone();
+
+        let connect_options = || {
+            let mut con_config = Connection::build(address.clone())
+                .version(version.clone())
+                .name(master_name.clone())
+                .identity(identity.clone())
+                .log_commands(verbose >= 1)
+                .log_packets(verbose >= 2)
+                .log_udp_packets(verbose >= 3);
+
+            if let Some(channel) = &channel {
+                con_config = con_config.channel(channel.clone());
+            }
+
+            con_config
+        };
+
+        let bot_addr = master.create(None).spawn(&mut Tokio::Global);
 
+        connect_with_retry(&bot_addr, connect_options, retry, &logger).await?;
+        trace!(logger, "Spawned master bot actor");
+
+        Ok(bot_addr)
+    }
+
+    /// Creates the master without connecting it to a server.
+    pub(crate) async fn new(args: MasterArgs, logger: Logger) -> anyhow::Result<Self> {
+        let identity = args.id.expect("identity should exist");
         let connection = TeamSpeakConnection::new(logger.clone()).await.unwrap();
         trace!(logger, "Created teamspeak connection");
 
+        let retry = ConnectRetry {
+            attempts: args.connect_attempts,
+            backoff: Duration::from_secs(args.connect_backoff),
+        };
+
         let config = MasterConfig {
             master_name: args.master_name,
             music_root: args.music_root,
             address: args.address,
             verbose: args.verbose,
             volume: args.volume,
+            retry,
+            admins: args.admins,
+            leave_warning: Duration::from_secs(args.leave_warning),
+            verbose_feedback: args.verbose_feedback,
+            avatar_from_thumbnail: args.avatar_from_thumbnail,
+            master_description: args.master_description,
+            description_interval: Duration::from_secs(args.description_interval),
+            playlist_dir: args.playlist_dir,
+            private_errors: args.private_errors,
+            state_labels: StateLabels {
+                playing: args.playing_label,
+                paused: args.paused_label,
+                stopped: args.stopped_label,
+            },
+            search_on_url_failure: args.search_on_url_failure,
+            domain_cooldown: Duration::from_secs(args.domain_cooldown),
+            resume_after_error: args.resume_after_error,
+            art_cache: ArtCache::new(args.art_cache_size),
+            master_plays: args.master_plays,
+            identity,
+            client_version: parse_client_version(args.client_version.as_deref())?,
+            queue_max_time: args.queue_max_time.map(Duration::from_millis),
+            queue_max_bytes: args.queue_max_bytes,
+            expand_playlists: args.expand_playlists,
+            announce_next: Duration::from_secs(args.announce_next),
+            show_volume_in_name: args.show_volume_in_name,
+            master_channel_message: args.master_channel_message,
+            resample_quality: args.resample_quality,
+            codec: parse_codec(args.codec.as_deref())?,
+            stall_timeout: Duration::from_secs(args.stall_timeout),
+            favorites_dir: args.favorites_dir,
+            playlist_max_len: args.playlist_max_len,
+            state_dir: args.state_dir,
+            blocklist: Blocklist::new(&args.blocklist),
         };
 
-        let bot_addr = Self {
+        Ok(Self {
             config,
             my_addr: None,
             teamspeak: connection,
-            logger: logger.clone(),
+            logger,
             rng: SmallRng::from_entropy(),
             available_names: args.names,
             available_ids: args.ids.expect("identities"),
             connected_bots: HashMap::new(),
-        }
-        .create(None)
-        .spawn(&mut Tokio::Global);
-
-        bot_addr.send(Connect(con_config)).await.unwrap().unwrap();
-        trace!(logger, "Spawned master bot actor");
-
-        bot_addr
+            player: None,
+            recent_tracks: VecDeque::new(),
+        })
     }
 
     async fn bot_args_for_client(
         &mut self,
         user_id: ClientId,
     ) -> std::result::Result<MusicBotArgs, BotCreationError> {
-        let channel = match self.teamspeak.channel_of_user(user_id).await.unwrap() {
+        let channel = match self.find_channel_of_user(user_id).await {
             Some(channel) => channel,
             None => return Err(BotCreationError::UnfoundUser),
         };
 
-        if Some(channel) == self.teamspeak.current_channel().await.unwrap() {
+        if Some(channel) == self.teamspeak.current_channel().await.unwrap_or(None) {
             return Err(BotCreationError::MasterChannel(
                 self.config.master_name.clone(),
             ));
@@ -115,12 +277,10 @@ impl MasterBot {
             }
         }
 
-        let channel_path = self
-            .teamspeak
-            .channel_path_of_user(user_id)
-            .await
-            .expect("can find poke sender")
-            .expect("can find poke sender");
+        let channel_path = match self.teamspeak.channel_path_of_user(user_id).await {
+            Ok(Some(path)) => path,
+            _ => return Err(BotCreationError::UnfoundUser),
+        };
 
         self.available_names.shuffle(&mut self.rng);
         let name = match self.available_names.pop() {
@@ -138,26 +298,98 @@ impl MasterBot {
             }
         };
 
-        Ok(MusicBotArgs {
-            name: name.clone(),
+        Ok(self.music_bot_args(name, identity, channel_path))
+    }
+
+    /// Looks up the channel of a user, if the user is unknown the master
+    /// subscribes to all channels and tries once more.
+    async fn find_channel_of_user(&mut self, user_id: ClientId) -> Option<ChannelId> {
+        if let Ok(Some(channel)) = self.teamspeak.channel_of_user(user_id).await {
+            return Some(channel);
+        }
+
+        debug!(self.logger, "User not found, subscribing to all channels"; "id" => user_id.0);
+        if let Err(e) = self.teamspeak.subscribe_all().await {
+            warn!(self.logger, "Failed to subscribe to all channels"; "error" => %e);
+            return None;
+        }
+        tokio::time::sleep(SUBSCRIBE_WAIT).await;
+
+        self.teamspeak.channel_of_user(user_id).await.ok().flatten()
+    }
+
+    fn music_bot_args(&self, name: String, identity: Identity, channel: String) -> MusicBotArgs {
+        let logger = self.logger.new(o!("musicbot" => name.clone()));
+
+        MusicBotArgs {
+            name,
             music_root: self.config.music_root.clone(),
             master: self.my_addr.clone(),
             address: self.config.address.clone(),
             identity,
             local: false,
-            channel: channel_path,
+            channel,
             verbose: self.config.verbose,
-            logger: self.logger.new(o!("musicbot" => name)),
+            logger,
             volume: self.config.volume,
-        })
+            retry: ConnectRetry {
+                attempts: self.config.retry.attempts.min(BOT_CONNECT_ATTEMPTS),
+                ..self.config.retry
+            },
+            admins: self.config.admins.clone(),
+            leave_warning: self.config.leave_warning,
+            verbose_feedback: self.config.verbose_feedback,
+            avatar_from_thumbnail: self.config.avatar_from_thumbnail,
+            playlist_dir: self.config.playlist_dir.clone(),
+            private_errors: self.config.private_errors,
+            state_labels: self.config.state_labels.clone(),
+            search_on_url_failure: self.config.search_on_url_failure,
+            domain_cooldown: self.config.domain_cooldown,
+            resume_after_error: self.config.resume_after_error,
+            art_cache: self.config.art_cache.clone(),
+            client_version: self.config.client_version.clone(),
+            queue_max_time: self.config.queue_max_time,
+            queue_max_bytes: self.config.queue_max_bytes,
+            expand_playlists: self.config.expand_playlists,
+            announce_next: self.config.announce_next,
+            show_volume_in_name: self.config.show_volume_in_name,
+            resample_quality: self.config.resample_quality,
+            codec: self.config.codec,
+            stall_timeout: self.config.stall_timeout,
+            favorites_dir: self.config.favorites_dir.clone(),
+            playlist_max_len: self.config.playlist_max_len,
+            state_dir: self.config.state_dir.clone(),
+            blocklist: self.config.blocklist.clone(),
+            connection: None,
+        }
     }
 
     async fn spawn_bot_for_client(&mut self, id: ClientId) -> anyhow::Result<()> {
         match self.bot_args_for_client(id).await {
             Ok(bot_args) => {
                 let name = bot_args.name.clone();
-                let bot = MusicBot::spawn(bot_args).await;
-                self.connected_bots.insert(name, bot);
+                let identity = bot_args.identity.clone();
+                match MusicBot::spawn(bot_args).await {
+                    Ok(bot) => {
+                        self.connected_bots.insert(name, bot);
+                    }
+                    Err(e) => {
+                        error!(
+                            self.logger,
+                            "Failed to spawn bot";
+                            "name" => &name,
+                            "error" => %e
+                        );
+                        self.available_names.push(name);
+                        self.available_ids.push(identity);
+                        self.teamspeak
+                            .send_message_to_user(id, String::from("Failed to connect the bot"))
+                            .await?;
+                    }
+                }
+            }
+            Err(e @ BotCreationError::MasterChannel(_)) => {
+                self.on_master_channel_poke(id, e).await?;
             }
             Err(e) => {
                 self.teamspeak
@@ -169,6 +401,59 @@ impl MasterBot {
         Ok(())
     }
 
+    /// Pokes from the channel of the master either make the master play itself
+    /// or are answered with the configured message.
+    async fn on_master_channel_poke(
+        &mut self,
+        id: ClientId,
+        error: BotCreationError,
+    ) -> anyhow::Result<()> {
+        if self.config.master_plays {
+            return self.start_master_player(id).await;
+        }
+
+        let msg = match &self.config.master_channel_message {
+            Some(msg) => msg.clone(),
+            None => error.to_string(),
+        };
+
+        self.teamspeak.send_message_to_user(id, msg).await
+    }
+
+    /// Lets the master play in its own channel by running a music bot over
+    /// the connection of the master.
+    async fn start_master_player(&mut self, id: ClientId) -> anyhow::Result<()> {
+        if self.player.is_some() {
+            return self
+                .teamspeak
+                .send_message_to_user(
+                    id,
+                    String::from("I am already playing here, use chat commands to control me"),
+                )
+                .await;
+        }
+
+        let name = self.config.master_name.clone();
+        let identity = self.config.identity.clone();
+        // The channel is only used for connecting which the player never does
+        let mut args = self.music_bot_args(name, identity, String::new());
+        args.connection = Some(self.teamspeak.clone());
+
+        match MusicBot::spawn(args).await {
+            Ok(player) => {
+                self.player = Some(player);
+            }
+            Err(e) => {
+                error!(self.logger, "Failed to start playing in the master channel"; "error" => %e);
+                self.teamspeak
+                    .send_message_to_user(id, String::from("Failed to start playing"))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
     async fn on_message(&mut self, message: MusicBotMessage) -> anyhow::Result<()> {
         match message {
             MusicBotMessage::TextMessage(message) => {
@@ -178,12 +463,25 @@ impl MasterBot {
                         "Poked, creating bot"; "user" => %who
                     );
                     self.spawn_bot_for_client(who).await?;
+                } else if let Some(command) = message.text.strip_prefix('!') {
+                    let handled = self.on_command(command.trim(), &message.invoker).await?;
+                    if let (false, Some(player)) = (handled, &self.player) {
+                        // The player may report back to the master, so do not wait for it
+                        tokio::spawn(player.send(MusicBotMessage::TextMessage(message)));
+                    }
+                }
+            }
+            msg @ MusicBotMessage::ClientChannel { .. }
+            | msg @ MusicBotMessage::ClientDisconnected { .. } => {
+                // The player leaves like other bots once the channel is empty
+                if let Some(player) = &self.player {
+                    tokio::spawn(player.send(msg));
                 }
             }
             MusicBotMessage::ClientAdded(id) => {
                 if id == self.teamspeak.my_id().await? {
                     self.teamspeak
-                        .set_description(String::from("Poke me if you want a music bot!"))
+                        .set_description(self.config.master_description.clone())
                         .await;
                 }
             }
@@ -193,6 +491,168 @@ impl MasterBot {
         Ok(())
     }
 
+    /// Returns false if the command is not one of the master.
+    async fn on_command(&mut self, command: &str, invoker: &Invoker) -> anyhow::Result<bool> {
+        let mut words = command.splitn(2, ' ');
+        let name = words.next().unwrap_or_default();
+        let arg = words.next().map(str::trim).filter(|arg| !arg.is_empty());
+        let admin = is_admin(&self.config.admins, invoker);
+
+        let reply = match (name, arg) {
+            ("stop-all", None) | ("leave-all", None) if !admin => {
+                String::from("Only admins can control all bots at once")
+            }
+            ("stop-all", None) => format!("Stopped {} bots", self.stop_all().await),
+            ("leave-all", None) => format!("Told {} bots to leave", self.leave_all()),
+            ("identities", _) if !admin => String::from("Only admins can manage identities"),
+            ("identities", None) => format!(
+                "{} identities are free, {} are in use",
+                self.available_ids.len(),
+                self.connected_bots.len()
+            ),
+            ("identities", Some(bot)) => self.switch_identity(bot).await?,
+            ("loglevel", None) => format!(
+                "Logging {} messages",
+                log_bridge::max_level().as_str().to_lowercase()
+            ),
+            ("loglevel", Some(_)) if !admin => String::from("Only admins can change the log level"),
+            ("loglevel", Some(level)) => match level.parse::<slog::Level>() {
+                Ok(level) => {
+                    log_bridge::set_max_level(level);
+                    info!(self.logger, "Changed log level"; "level" => level.as_str());
+                    format!("Logging {} messages now", level.as_str().to_lowercase())
+                }
+                Err(()) => {
+                    String::from("Expected one of trace, debug, info, warning, error or critical")
+                }
+            },
+            _ => return Ok(false),
+        };
+
+        self.teamspeak
+            .send_message_to_user(invoker.id, reply)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Reconnects a bot with a free identity in case the server flagged its
+    /// current one, the queue is kept but the current track is not resumed.
+    /// If reconnecting fails, the queue is saved to `playlist_dir`.
+    async fn switch_identity(&mut self, name: &str) -> anyhow::Result<String> {
+        let bot = match self.connected_bots.get(name) {
+            Some(bot) => bot.clone(),
+            None => return Ok(format!("There is no bot called {}", name)),
+        };
+        if self.available_ids.is_empty() {
+            return Ok(String::from("There is no free identity to switch to"));
+        }
+        let channel = match bot.send(GetChannelPath).await?? {
+            Some(channel) => channel,
+            None => return Ok(format!("Failed to find the channel of {}", name)),
+        };
+
+        let playlist = bot.send(TakePlaylist).await?;
+        let old_identity = bot.send(GetIdentity).await?;
+        bot.send(Quit(String::from("Switching identity"))).await??;
+        self.connected_bots.remove(name);
+
+        // There was a free identity before the old one is returned
+        let identity = self.available_ids.remove(0);
+        self.available_ids.push(old_identity);
+        let bot_args = self.music_bot_args(name.to_owned(), identity.clone(), channel);
+        match MusicBot::spawn(bot_args).await {
+            Ok(bot) => {
+                bot.send(SetPlaylist(playlist)).await?;
+                self.connected_bots.insert(name.to_owned(), bot);
+
+                Ok(format!("{} reconnected with a new identity", name))
+            }
+            Err(e) => {
+                error!(self.logger, "Failed to respawn bot"; "name" => name, "error" => %e);
+                self.available_names.push(name.to_owned());
+                self.available_ids.push(identity);
+
+                if playlist.is_empty() {
+                    return Ok(format!("Failed to reconnect {}", name));
+                }
+
+                // The old bot is gone, so the queue is kept where !restore finds it
+                let saved = saved_queue_path(self.config.playlist_dir.as_deref(), name)
+                    .and_then(|path| write_tracks(&path, &playlist));
+                match saved {
+                    Ok(()) => Ok(format!(
+                        "Failed to reconnect {}, use !restore {} to get its {} tracks back",
+                        name,
+                        name,
+                        playlist.len()
+                    )),
+                    Err(e) => {
+                        error!(self.logger, "Failed to save queue"; "name" => name, "error" => %e);
+                        Ok(format!(
+                            "Failed to reconnect {}, its {} tracks were lost",
+                            name,
+                            playlist.len()
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
+    async fn stop_all(&self) -> usize {
+        let futures = self
+            .connected_bots
+            .values()
+            .map(|b| b.send(ExecuteCommand(Command::Stop)));
+        for res in future::join_all(futures).await {
+            match res {
+                Ok(Err(e)) => error!(self.logger, "Failed to stop bot"; "error" => %e),
+                Err(e) => error!(self.logger, "Failed to stop bot"; "error" => %e),
+                Ok(Ok(())) => (),
+            }
+        }
+
+        self.connected_bots.len()
+    }
+
+    fn leave_all(&self) -> usize {
+        for bot in self.connected_bots.values() {
+            // Leaving bots report back to the master, so waiting for them here would deadlock
+            tokio::spawn(bot.send(ExecuteCommand(Command::Leave)));
+        }
+
+        self.connected_bots.len()
+    }
+
+    /// Restores the description if it was changed by someone else.
+    async fn assert_description(&mut self) -> anyhow::Result<()> {
+        let wanted = &self.config.master_description;
+        if self.teamspeak.description().await?.as_ref() != Some(wanted) {
+            info!(self.logger, "Restoring master description");
+            self.teamspeak.set_description(wanted.clone()).await;
+        }
+
+        Ok(())
+    }
+
+    fn start_description_ticker(&self, addr: WeakAddress<Self>) {
+        let interval = self.config.description_interval;
+        if interval == Duration::from_secs(0) {
+            return;
+        }
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // Stops once the master bot is gone
+                if addr.send(AssertDescription).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     pub async fn bot_data(&self, name: String) -> Option<crate::web_server::BotData> {
         let bot = self.connected_bots.get(&name)?;
 
@@ -210,6 +670,54 @@ impl MasterBot {
         result
     }
 
+    pub async fn bot_command(&self, name: String, command: Command) -> Option<()> {
+        let bot = self.connected_bots.get(&name)?;
+
+        if let Err(e) = bot.send(ExecuteCommand(command)).await.ok()? {
+            error!(self.logger, "Failed to execute web command"; "bot" => &name, "error" => %e);
+        }
+
+        Some(())
+    }
+
+    pub fn art(&self, id: &str) -> Option<Arc<Art>> {
+        self.config.art_cache.get(id)
+    }
+
+    pub async fn bot_volume_updates(&self, name: String) -> Option<watch::Receiver<f64>> {
+        let bot = self.connected_bots.get(&name)?;
+
+        bot.send(SubscribeVolume).await.ok()
+    }
+
+    pub async fn bot_add_audio(
+        &self,
+        name: String,
+        location: AudioLocation,
+        user: String,
+    ) -> Option<anyhow::Result<()>> {
+        let bot = self.connected_bots.get(&name)?;
+
+        bot.send(AddAudio { location, user }).await.ok()
+    }
+
+    pub async fn bot_set_volume(&self, name: String, volume: f64) -> Option<anyhow::Result<()>> {
+        let bot = self.connected_bots.get(&name)?;
+
+        bot.send(SetVolume(volume)).await.ok()
+    }
+
+    pub async fn bot_reorder(
+        &self,
+        name: String,
+        order: Vec<usize>,
+        version: u64,
+    ) -> Option<Result<(), ReorderError>> {
+        let bot = self.connected_bots.get(&name)?;
+
+        bot.send(ReorderPlaylist { order, version }).await.ok()
+    }
+
     pub fn bot_names(&self) -> Vec<String> {
         let len = self.connected_bots.len();
         let mut result = Vec::with_capacity(len);
@@ -220,6 +728,78 @@ impl MasterBot {
         result
     }
 
+    async fn transfer_playlist(&self, from: &str, to: &str) -> Result<usize, TransferError> {
+        if from == to {
+            return Err(TransferError::SameBot);
+        }
+
+        // The target is checked first so that the playlist is not taken for nothing
+        let target = self
+            .connected_bots
+            .get(to)
+            .filter(|target| target.is_connected())
+            .ok_or_else(|| TransferError::UnknownBot(to.to_owned()))?;
+        let source = self
+            .connected_bots
+            .get(from)
+            .ok_or_else(|| TransferError::UnknownBot(from.to_owned()))?;
+
+        let playlist = source
+            .send(TakePlaylist)
+            .await
+            .map_err(|_| TransferError::UnknownBot(from.to_owned()))?;
+        if playlist.is_empty() {
+            return Err(TransferError::EmptyPlaylist);
+        }
+
+        let append = AppendPlaylist {
+            from: from.to_owned(),
+            playlist: playlist.clone(),
+        };
+        // The tracks that can not be transferred go back to the source
+        let (transferred, left_out) = match target.send(append).await {
+            Ok(res) => res,
+            // The target quit in the meantime
+            Err(_) => {
+                self.return_playlist(source, playlist).await;
+                return Err(TransferError::UnknownBot(to.to_owned()));
+            }
+        };
+        if !left_out.is_empty() {
+            self.return_playlist(source, left_out).await;
+        }
+
+        Ok(transferred)
+    }
+
+    async fn return_playlist(&self, source: &Address<MusicBot>, playlist: Vec<AudioMetadata>) {
+        let count = playlist.len();
+        if source.send(SetPlaylist(playlist)).await.is_err() {
+            error!(self.logger, "Lost the transferred playlist"; "count" => count);
+        }
+    }
+
+    fn on_track_started(&mut self, bot: String, channel: Option<String>, track: AudioMetadata) {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if self.recent_tracks.len() >= MAX_RECENT_TRACKS {
+            self.recent_tracks.pop_back();
+        }
+        self.recent_tracks.push_front(RecentTrack {
+            bot,
+            channel,
+            started_at,
+            track,
+        });
+    }
+
+    pub fn recent_tracks(&self) -> Vec<RecentTrack> {
+        self.recent_tracks.iter().cloned().collect()
+    }
+
     fn on_bot_disconnect(&mut self, name: String, id: Identity) {
         self.connected_bots.remove(&name);
         self.available_names.push(name);
@@ -230,7 +810,8 @@ impl MasterBot {
         let futures = self
             .connected_bots
             .values()
-            .map(|b| b.send(Quit(reason.clone())));
+            .chain(self.player.as_ref())
+            .map(|b| b.send(Shutdown(reason.clone())));
         for res in future::join_all(futures).await {
             if let Err(e) = res {
                 error!(self.logger, "Failed to shut down bot"; "error" => %e);
@@ -247,6 +828,20 @@ impl Actor for MasterBot {
     }
 }
 
+pub struct AssertDescription;
+impl Message for AssertDescription {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<AssertDescription> for MasterBot {
+    async fn handle(&mut self, _: AssertDescription, _: &mut Context<Self>) {
+        if let Err(e) = self.assert_description().await {
+            warn!(self.logger, "Failed to check description"; "error" => %e);
+        }
+    }
+}
+
 pub struct Connect(pub ConnectOptions);
 impl Message for Connect {
     type Result = anyhow::Result<()>;
@@ -256,11 +851,151 @@ impl Message for Connect {
 impl Handler<Connect> for MasterBot {
     async fn handle(&mut self, opt: Connect, ctx: &mut Context<Self>) -> anyhow::Result<()> {
         let addr = ctx.address().unwrap();
-        self.teamspeak.connect_for_bot(opt.0, addr.downgrade())?;
+        self.teamspeak
+            .connect_for_bot(opt.0, addr.downgrade())
+            .await?;
+        self.start_description_ticker(addr.downgrade());
         Ok(())
     }
 }
 
+/// Checks if the unique identifier of `invoker` is in the list of admins.
+/// Invokers without one, like stdin or the web interface, are never admins.
+pub fn is_admin(admins: &[String], invoker: &Invoker) -> bool {
+    match &invoker.uid {
+        Some(uid) => admins.contains(&base64::encode(&uid.0)),
+        None => false,
+    }
+}
+
+/// Identifies the user behind `invoker` for anything that is tracked per user.
+///
+/// Falls back to the name and client id if there is no unique identifier,
+/// which only stays the same until the client reconnects.
+pub fn user_key(invoker: &Invoker) -> String {
+    match &invoker.uid {
+        Some(uid) => base64::encode(&uid.0),
+        None => format!("{}#{}", invoker.name, invoker.id.0),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectRetry {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+/// Client versions that can be selected with the `client_version` option
+const CLIENT_VERSIONS: &[(&str, Version)] = &[
+    ("linux-3.3.2", Version::Linux_3_3_2),
+    ("linux-3.x.x", Version::Linux_3_X_X__1),
+    ("windows-3.x.x", Version::Windows_3_X_X__1),
+    ("macos-3.x.x", Version::MacOs_3_X_X__1),
+];
+
+/// Codecs that can be selected with the `codec` option
+const CODECS: &[(&str, CodecType)] = &[
+    ("opus-music", CodecType::OpusMusic),
+    ("opus-voice", CodecType::OpusVoice),
+];
+
+/// Looks up the codec audio is sent with, Opus Music is used if none is
+/// configured.
+pub fn parse_codec(name: Option<&str>) -> anyhow::Result<CodecType> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(CodecType::OpusMusic),
+    };
+
+    match CODECS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+    {
+        Some((_, codec)) => Ok(*codec),
+        None => {
+            let known: Vec<_> = CODECS.iter().map(|(known, _)| *known).collect();
+            Err(anyhow::anyhow!(
+                "Unknown codec \"{}\", expected one of: {}",
+                name,
+                known.join(", ")
+            ))
+        }
+    }
+}
+
+/// Looks up the client version to connect with, Linux 3.3.2 is used if none
+/// is configured.
+pub fn parse_client_version(name: Option<&str>) -> anyhow::Result<Version> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(Version::Linux_3_3_2),
+    };
+
+    match CLIENT_VERSIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+    {
+        Some((_, version)) => Ok(version.clone()),
+        None => {
+            let known: Vec<_> = CLIENT_VERSIONS.iter().map(|(known, _)| *known).collect();
+            Err(anyhow::anyhow!(
+                "Unknown client_version \"{}\", expected one of: {}",
+                name,
+                known.join(", ")
+            ))
+        }
+    }
+}
+
+/// Sends `Connect` to a bot until it succeeds, doubling the backoff after
+/// every failed attempt up to `MAX_CONNECT_BACKOFF`.
+pub async fn connect_with_retry<A, F>(
+    addr: &Address<A>,
+    connect_options: F,
+    retry: ConnectRetry,
+    logger: &Logger,
+) -> anyhow::Result<()>
+where
+    A: Handler<Connect>,
+    F: Fn() -> ConnectOptions,
+{
+    let attempts = retry.attempts.max(1);
+    let mut backoff = retry.backoff.min(MAX_CONNECT_BACKOFF);
+
+    for attempt in 1..=attempts {
+        let res = match addr.send(Connect(connect_options())).await {
+            Ok(res) => res,
+            Err(e) => Err(anyhow::anyhow!("{}", e)),
+        };
+
+        match res {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    logger,
+                    "Failed to connect, retrying";
+                    "attempt" => attempt,
+                    "attempts" => attempts,
+                    "backoff" => %humantime::format_duration(backoff),
+                    "error" => %e
+                );
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+            }
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to connect after {} attempts: {}",
+                    attempts,
+                    e
+                ))
+            }
+        }
+    }
+
+    unreachable!("the last attempt always returns")
+}
+
 pub struct Quit(pub String);
 impl Message for Quit {
     type Result = anyhow::Result<()>;
@@ -289,6 +1024,37 @@ impl Handler<BotDisonnected> for MasterBot {
     }
 }
 
+/// Sent by the player in the channel of the master when it stops playing.
+pub struct PlayerStopped;
+
+impl Message for PlayerStopped {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<PlayerStopped> for MasterBot {
+    async fn handle(&mut self, _: PlayerStopped, _: &mut Context<Self>) {
+        self.player = None;
+    }
+}
+
+pub struct TrackStarted {
+    pub bot: String,
+    pub channel: Option<String>,
+    pub track: AudioMetadata,
+}
+
+impl Message for TrackStarted {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<TrackStarted> for MasterBot {
+    async fn handle(&mut self, t: TrackStarted, _: &mut Context<Self>) {
+        self.on_track_started(t.bot, t.channel, t.track);
+    }
+}
+
 #[async_trait]
 impl Handler<MusicBotMessage> for MasterBot {
     async fn handle(&mut self, msg: MusicBotMessage, _: &mut Context<Self>) -> anyhow::Result<()> {
@@ -296,6 +1062,46 @@ impl Handler<MusicBotMessage> for MasterBot {
     }
 }
 
+pub struct TransferPlaylist {
+    pub from: String,
+    pub to: String,
+    pub invoker: ClientId,
+}
+
+impl Message for TransferPlaylist {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<TransferPlaylist> for MasterBot {
+    async fn handle(&mut self, t: TransferPlaylist, _: &mut Context<Self>) -> anyhow::Result<()> {
+        let msg = match self.transfer_playlist(&t.from, &t.to).await {
+            Ok(count) => format!("Transferred {} tracks from {} to {}", count, t.from, t.to),
+            Err(e) => e.to_string(),
+        };
+
+        self.teamspeak.send_message_to_user(t.invoker, msg).await
+    }
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    UnknownBot(String),
+    SameBot,
+    EmptyPlaylist,
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TransferError::*;
+        match self {
+            UnknownBot(name) => write!(f, "There is no bot called \"{}\"", name),
+            SameBot => write!(f, "Can't transfer the playlist to the same bot"),
+            EmptyPlaylist => write!(f, "There is nothing to transfer"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum BotCreationError {
     UnfoundUser,
@@ -327,6 +1133,26 @@ impl std::fmt::Display for BotCreationError {
     }
 }
 
+/// Serialized as `{"kind": "...", "message": "..."}` so API clients can
+/// match on the kind instead of the message.
+impl Serialize for BotCreationError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use BotCreationError::*;
+        let kind = match self {
+            UnfoundUser => "unfound_user",
+            MasterChannel(_) => "master_channel",
+            MultipleBots(_) => "multiple_bots",
+            OutOfNames => "out_of_names",
+            OutOfIdentities => "out_of_identities",
+        };
+
+        let mut state = serializer.serialize_struct("BotCreationError", 2)?;
+        state.serialize_field("kind", kind)?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
 fn default_name() -> String {
     String::from("PokeBot")
 }
@@ -335,6 +1161,54 @@ fn default_verbose() -> u8 {
     0
 }
 
+fn default_leave_warning() -> u64 {
+    30
+}
+
+fn default_master_description() -> String {
+    String::from("Poke me if you want a music bot!")
+}
+
+fn default_description_interval() -> u64 {
+    60
+}
+
+fn default_playing_label() -> String {
+    String::from("Playing")
+}
+
+fn default_paused_label() -> String {
+    String::from("Paused")
+}
+
+fn default_stopped_label() -> String {
+    String::from("Stopped")
+}
+
+fn default_art_cache_size() -> usize {
+    100
+}
+
+fn default_max_upload_size() -> usize {
+    50 * 1024 * 1024
+}
+
+fn default_show_volume_in_name() -> bool {
+    true
+}
+
+fn default_stall_timeout() -> u64 {
+    30
+}
+
+fn default_connect_attempts() -> u32 {
+    5
+}
+
+fn default_connect_backoff() -> u64 {
+    2
+}
+
 impl MasterArgs {
     pub fn merge(self, args: Args) -> Self {
         let address = args.address.unwrap_or(self.address);
@@ -354,6 +1228,41 @@ impl MasterArgs {
             domain: self.domain,
             bind_address: self.bind_address,
             webserver_enable: self.webserver_enable,
+            cors_origins: self.cors_origins,
+            upload_dir: self.upload_dir,
+            max_upload_size: self.max_upload_size,
+            admins: self.admins,
+            leave_warning: self.leave_warning,
+            verbose_feedback: self.verbose_feedback,
+            avatar_from_thumbnail: self.avatar_from_thumbnail,
+            master_description: self.master_description,
+            description_interval: self.description_interval,
+            playlist_dir: self.playlist_dir,
+            private_errors: self.private_errors,
+            playing_label: self.playing_label,
+            paused_label: self.paused_label,
+            stopped_label: self.stopped_label,
+            search_on_url_failure: self.search_on_url_failure,
+            domain_cooldown: self.domain_cooldown,
+            resume_after_error: self.resume_after_error,
+            art_cache_size: self.art_cache_size,
+            master_plays: self.master_plays,
+            client_version: self.client_version,
+            queue_max_time: self.queue_max_time,
+            queue_max_bytes: self.queue_max_bytes,
+            expand_playlists: self.expand_playlists,
+            announce_next: self.announce_next,
+            show_volume_in_name: self.show_volume_in_name,
+            master_channel_message: self.master_channel_message,
+            resample_quality: self.resample_quality,
+            codec: self.codec,
+            stall_timeout: self.stall_timeout,
+            favorites_dir: self.favorites_dir,
+            playlist_max_len: self.playlist_max_len,
+            state_dir: self.state_dir,
+            blocklist: self.blocklist,
+            connect_attempts: self.connect_attempts,
+            connect_backoff: self.connect_backoff,
             id: self.id,
             channel,
             verbose,
@@ -368,4 +1277,57 @@ pub struct MasterConfig {
     pub address: String,
     pub verbose: u8,
     pub volume: f64,
+    pub retry: ConnectRetry,
+    pub admins: Vec<String>,
+    pub leave_warning: Duration,
+    pub verbose_feedback: bool,
+    pub avatar_from_thumbnail: bool,
+    pub master_description: String,
+    pub description_interval: Duration,
+    pub playlist_dir: Option<PathBuf>,
+    pub private_errors: bool,
+    pub state_labels: StateLabels,
+    pub search_on_url_failure: bool,
+    pub domain_cooldown: Duration,
+    pub resume_after_error: bool,
+    pub art_cache: ArtCache,
+    pub master_plays: bool,
+    pub identity: Identity,
+    pub client_version: Version,
+    pub queue_max_time: Option<Duration>,
+    pub queue_max_bytes: Option<u32>,
+    pub expand_playlists: bool,
+    pub announce_next: Duration,
+    pub show_volume_in_name: bool,
+    pub master_channel_message: Option<String>,
+    pub resample_quality: Option<i32>,
+    pub codec: CodecType,
+    pub stall_timeout: Duration,
+    pub favorites_dir: Option<PathBuf>,
+    pub playlist_max_len: Option<usize>,
+    pub state_dir: Option<PathBuf>,
+    pub blocklist: Blocklist,
+}
+
+#[cfg(test)]
+mod tests {
+    use tsclientlib::{ClientId, Invoker};
+
+    use super::{is_admin, user_key};
+
+    /// The invoker used for commands read from stdin
+    fn stdin_invoker() -> Invoker {
+        Invoker {
+            name: String::from("stdin"),
+            id: ClientId(0),
+            uid: None,
+        }
+    }
+
+    #[test]
+    fn invoker_without_uid() {
+        let invoker = stdin_invoker();
+        assert!(!is_admin(&[String::from("c3RkaW4=")], &invoker));
+        assert_eq!(user_key(&invoker), "stdin#0");
+    }
 }
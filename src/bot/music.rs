@@ -1,27 +1,61 @@
+use std::cmp::Ordering;
 use std::ffi::OsStr;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
+use rand::{rngs::SmallRng, SeedableRng};
 use serde::Serialize;
 use slog::{debug, error, info, trace, warn, Logger};
 use structopt::StructOpt;
-use tsclientlib::{data, ChannelId, ClientId, Connection, Identity, Invoker, MessageTarget};
+use tokio::sync::watch;
+use tsclientlib::{
+    data, ChannelId, ClientId, Connection, Identity, Invoker, MessageTarget, Version,
+};
+use tsproto_packets::packets::CodecType;
 use walkdir::WalkDir;
 use xtra::{spawn::Tokio, Actor, Address, Context, Handler, Message, WeakAddress};
 
-use crate::audio_player::AudioPlayer;
-use crate::bot::{BotDisonnected, Connect, MasterBot, Quit};
-use crate::command::Command;
-use crate::command::VolumeChange;
-use crate::playlist::Playlist;
+use crate::art_cache::ArtCache;
+use crate::audio_player::{AudioPlayer, AudioPlayerError};
+use crate::bot::blocklist::Blocklist;
+use crate::bot::bulk::BulkResults;
+use crate::bot::favorites::{Favorite, Favorites};
+use crate::bot::{
+    connect_with_retry, is_admin, user_key, BotDisonnected, Connect, ConnectRetry, MasterBot,
+    PlayerStopped, Quit, TrackStarted, TransferPlaylist,
+};
+use crate::command::{
+    self, ClipRange, ClipRangeError, Command, QueueOrder, RepeatMode, Seek, VolumeChange,
+};
+use crate::playlist::{Playlist, PlaylistFull, ReorderError};
 use crate::teamspeak as ts;
 use crate::youtube_dl::AudioMetadata;
 use ts::TeamSpeakConnection;
 
 static FILE_PREFIX: &str = "file://";
 
+/// How often the position is checked to announce the next track and to
+/// detect stuck playback
+const POSITION_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the state of a bot is logged
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest nickname TeamSpeak allows
+const MAX_NICKNAME_LENGTH: usize = 30;
+
+/// Number of tracks `!queue` lists at most
+const MAX_QUEUE_LINES: usize = 20;
+
+/// Minimum time between two nickname changes
+const NICKNAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a change of the playlist can be undone
+const UNDO_WINDOW: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Debug)]
 pub struct ChatMessage {
     pub target: MessageTarget,
@@ -53,6 +87,24 @@ impl std::fmt::Display for State {
     }
 }
 
+/// Text shown in the nickname for each state, e.g. emojis instead of words
+#[derive(Debug, Clone)]
+pub struct StateLabels {
+    pub playing: String,
+    pub paused: String,
+    pub stopped: String,
+}
+
+impl StateLabels {
+    fn label(&self, state: State) -> &str {
+        match state {
+            State::Playing => &self.playing,
+            State::Paused => &self.paused,
+            State::Stopped | State::EndOfStream => &self.stopped,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum MusicBotMessage {
     TextMessage(ChatMessage),
@@ -79,8 +131,43 @@ pub enum AudioLocation {
     Path(PathBuf),
 }
 
+/// Where a command came from, allows replying to the user who issued it.
+struct CommandContext {
+    invoker: Invoker,
+}
+
+impl CommandContext {
+    /// Commands issued through the web interface
+    fn web() -> Self {
+        Self {
+            invoker: Invoker {
+                name: String::from("web"),
+                id: ClientId(0),
+                uid: None,
+            },
+        }
+    }
+
+    /// Commands from the web interface or stdin have no user to reply to
+    fn is_from_user(&self) -> bool {
+        self.invoker.uid.is_some()
+    }
+
+    fn user_key(&self) -> String {
+        user_key(&self.invoker)
+    }
+}
+
+struct UndoSnapshot {
+    tracks: Vec<AudioMetadata>,
+    taken: Instant,
+}
+
 pub struct MusicBot {
     name: String,
+    /// Shown in the nickname instead of `name`, which stays the same so that
+    /// the master and the web interface still find the bot
+    display_name: String,
     music_root: Option<PathBuf>,
     identity: Identity,
     player: AudioPlayer,
@@ -88,6 +175,56 @@ pub struct MusicBot {
     master: Option<WeakAddress<MasterBot>>,
     playlist: Playlist,
     state: State,
+    default_volume: f64,
+    admins: Vec<String>,
+    my_addr: Option<WeakAddress<Self>>,
+    my_id: Option<ClientId>,
+    leave_warning: Duration,
+    leave_generation: u64,
+    pending_leave: Option<u64>,
+    verbose_feedback: bool,
+    avatar_from_thumbnail: bool,
+    playlist_dir: Option<PathBuf>,
+    private_errors: bool,
+    state_labels: StateLabels,
+    search_on_url_failure: bool,
+    domain_cooldown: Duration,
+    volume_updates: watch::Sender<f64>,
+    art_cache: ArtCache,
+    /// Set if the connection belongs to the master, the bot then must not
+    /// change its name, description or avatar and must not disconnect.
+    shared_connection: bool,
+    expand_playlists: bool,
+    /// Only admins can change the playlist while it is locked
+    queue_locked: bool,
+    /// Volume from before the bot was muted
+    muted_volume: Option<f64>,
+    repeat: RepeatMode,
+    rng: SmallRng,
+    /// Set while a track is skipped so that it is not repeated
+    skipping: bool,
+    /// Playlist from before the last undoable change
+    undo: Option<UndoSnapshot>,
+    /// Track that was started last, it can still be seeked in once it ended
+    last_track: Option<AudioMetadata>,
+    last_nickname_update: Option<Instant>,
+    /// Nickname that is set once `NICKNAME_INTERVAL` passed
+    pending_nickname: Option<String>,
+    /// How long before the end of a track the next one is announced, 0 disables it
+    announce_next: Duration,
+    /// Set once the next track was announced for the current one
+    announced_next: bool,
+    show_volume_in_name: bool,
+    codec: CodecType,
+    /// How long the position may stay the same while playing, 0 disables the check
+    stall_timeout: Duration,
+    favorites: Option<Favorites>,
+    /// Last position of the player and since when it stayed there
+    last_progress: Option<(Duration, Instant)>,
+    last_heartbeat: Instant,
+    /// Where the queue is saved on shutdown, it is restored on the next start
+    state_dir: Option<PathBuf>,
+    blocklist: Blocklist,
     logger: Logger,
 }
 
@@ -102,39 +239,130 @@ pub struct MusicBotArgs {
     pub verbose: u8,
     pub logger: Logger,
     pub volume: f64,
+    pub retry: ConnectRetry,
+    pub admins: Vec<String>,
+    pub leave_warning: Duration,
+    pub verbose_feedback: bool,
+    pub avatar_from_thumbnail: bool,
+    pub playlist_dir: Option<PathBuf>,
+    pub private_errors: bool,
+    pub state_labels: StateLabels,
+    pub search_on_url_failure: bool,
+    pub domain_cooldown: Duration,
+    pub resume_after_error: bool,
+    pub art_cache: ArtCache,
+    /// Play over an existing connection instead of connecting on its own
+    pub connection: Option<TeamSpeakConnection>,
+    pub client_version: Version,
+    pub queue_max_time: Option<Duration>,
+    pub queue_max_bytes: Option<u32>,
+    pub expand_playlists: bool,
+    pub announce_next: Duration,
+    pub show_volume_in_name: bool,
+    pub resample_quality: Option<i32>,
+    pub codec: CodecType,
+    pub stall_timeout: Duration,
+    pub favorites_dir: Option<PathBuf>,
+    pub playlist_max_len: Option<usize>,
+    pub state_dir: Option<PathBuf>,
+    pub blocklist: Blocklist,
 }
 
 impl MusicBot {
-    pub async fn spawn(args: MusicBotArgs) -> Address<Self> {
-        let mut player = AudioPlayer::new(args.logger.clone()).unwrap();
+    pub async fn spawn(args: MusicBotArgs) -> anyhow::Result<Address<Self>> {
+        let mut player = AudioPlayer::new(args.logger.clone())?;
         player
             .change_volume(VolumeChange::Absolute(args.volume))
             .unwrap();
+        player.set_resume_after_error(args.resume_after_error);
+        player.set_queue_size(args.queue_max_time, args.queue_max_bytes);
+        player.set_resample_quality(args.resample_quality)?;
+        player.set_voice(args.codec == CodecType::OpusVoice);
 
-        let playlist = Playlist::new(args.logger.clone());
+        let playlist = Playlist::new(args.logger.clone()).with_max_len(args.playlist_max_len);
 
+        let shared_connection = args.connection.is_some();
         let teamspeak = if args.local {
             info!(args.logger, "Starting in CLI mode");
-            player.setup_with_audio_callback(None).unwrap();
+            player.setup_with_audio_callback(None)?;
 
             None
+        } else if let Some(connection) = args.connection {
+            info!(args.logger, "Playing over the connection of the master");
+            player.setup_with_audio_callback(Some(audio_callback(
+                connection.clone(),
+                args.codec,
+                args.logger.clone(),
+            )))?;
+
+            Some(connection)
         } else {
             Some(TeamSpeakConnection::new(args.logger.clone()).await.unwrap())
         };
-        let bot = Self {
+        let mut bot = Self {
             name: args.name.clone(),
+            display_name: args.name.clone(),
             music_root: args.music_root,
             master: args.master,
             identity: args.identity.clone(),
             player,
             teamspeak,
             playlist,
-            state: State::EndOfStream,
+            // The first `EndOfStream` then starts a queue restored from `state_dir`
+            state: State::Stopped,
+            default_volume: args.volume,
+            admins: args.admins,
+            my_addr: None,
+            my_id: None,
+            leave_warning: args.leave_warning,
+            leave_generation: 0,
+            pending_leave: None,
+            verbose_feedback: args.verbose_feedback,
+            avatar_from_thumbnail: args.avatar_from_thumbnail,
+            playlist_dir: args.playlist_dir,
+            private_errors: args.private_errors,
+            state_labels: args.state_labels,
+            search_on_url_failure: args.search_on_url_failure,
+            domain_cooldown: args.domain_cooldown,
+            volume_updates: watch::channel(args.volume).0,
+            art_cache: args.art_cache,
+            shared_connection,
+            expand_playlists: args.expand_playlists,
+            queue_locked: false,
+            muted_volume: None,
+            repeat: RepeatMode::Off,
+            rng: SmallRng::from_entropy(),
+            skipping: false,
+            undo: None,
+            last_track: None,
+            last_nickname_update: None,
+            pending_nickname: None,
+            announce_next: args.announce_next,
+            announced_next: false,
+            show_volume_in_name: args.show_volume_in_name,
+            codec: args.codec,
+            stall_timeout: args.stall_timeout,
+            favorites: args.favorites_dir.map(Favorites::new),
+            last_progress: None,
+            last_heartbeat: Instant::now(),
+            state_dir: args.state_dir,
+            blocklist: args.blocklist,
             logger: args.logger.clone(),
         };
+        if let Err(e) = bot.restore_state() {
+            warn!(args.logger, "Failed to restore queue"; "error" => %e);
+        }
 
         let bot_addr = bot.create(None).spawn(&mut Tokio::Global);
 
+        if shared_connection {
+            bot_addr
+                .send(MusicBotMessage::StateChange(State::EndOfStream))
+                .await??;
+
+            return Ok(bot_addr);
+        }
+
         if args.local {
             info!(args.logger, "Starting in local mode");
         } else {
@@ -147,15 +375,25 @@ impl MusicBot {
             );
         }
 
-        let opt = Connection::build(args.address)
-            .version(tsclientlib::Version::Linux_3_3_2)
-            .name(format!("🎵 {}", args.name))
-            .identity(args.identity)
-            .log_commands(args.verbose >= 1)
-            .log_packets(args.verbose >= 2)
-            .log_udp_packets(args.verbose >= 3)
-            .channel(args.channel);
-        bot_addr.send(Connect(opt)).await.unwrap().unwrap();
+        let (address, name, identity, channel, verbose, version) = (
+            &args.address,
+            &args.name,
+            &args.identity,
+            &args.channel,
+            args.verbose,
+            &args.client_version,
+        );
+        let connect_options = || {
+            Connection::build(address.clone())
+                .version(version.clone())
+                .name(format!("🎵 {}", name))
+                .identity(identity.clone())
+                .log_commands(verbose >= 1)
+                .log_packets(verbose >= 2)
+                .log_udp_packets(verbose >= 3)
+                .channel(channel.clone())
+        };
+        connect_with_retry(&bot_addr, connect_options, args.retry, &args.logger).await?;
 
         if args.local {
             debug!(args.logger, "Spawning stdin reader thread");
@@ -164,11 +402,9 @@ impl MusicBot {
 
         bot_addr
             .send(MusicBotMessage::StateChange(State::EndOfStream))
-            .await
-            .unwrap()
-            .unwrap();
+            .await??;
 
-        bot_addr
+        Ok(bot_addr)
     }
 
     pub fn name(&self) -> &str {
@@ -189,12 +425,27 @@ impl MusicBot {
         ts.current_channel().await
     }
 
+    /// Returns the client id of the bot, it is cached after the first request.
+    async fn my_id(&mut self) -> anyhow::Result<Option<ClientId>> {
+        if self.my_id.is_none() {
+            if let Some(ts) = &mut self.teamspeak {
+                self.my_id = Some(ts.my_id().await?);
+            }
+        }
+
+        Ok(self.my_id)
+    }
+
     async fn user_count(&mut self, channel: ChannelId) -> anyhow::Result<u32> {
         let ts = self.teamspeak.as_mut().expect("user_count needs ts");
 
         ts.user_count(channel).await
     }
 
+    fn is_admin(&self, invoker: &Invoker) -> bool {
+        is_admin(&self.admins, invoker)
+    }
+
     async fn send_message(&mut self, text: String) -> anyhow::Result<()> {
         debug!(self.logger, "Sending message to TeamSpeak"; "message" => &text);
 
@@ -205,7 +456,70 @@ impl MusicBot {
         Ok(())
     }
 
+    /// Sends confirmations which are only wanted if `verbose_feedback` is set
+    async fn send_feedback(&mut self, text: String) -> anyhow::Result<()> {
+        if self.verbose_feedback {
+            self.send_message(text).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a message only to the user who issued a command,
+    /// falls back to the channel if there is no such user.
+    async fn reply_to_user(&mut self, ctx: &CommandContext, text: String) -> anyhow::Result<()> {
+        if ctx.is_from_user() {
+            if let Some(ts) = &mut self.teamspeak {
+                return ts.send_message_to_user(ctx.invoker.id, text).await;
+            }
+        }
+
+        self.send_message(text).await
+    }
+
+    /// Sends errors to the invoker instead of the channel if `private_errors` is set
+    async fn send_error(&mut self, ctx: &CommandContext, text: String) -> anyhow::Result<()> {
+        if self.private_errors {
+            self.reply_to_user(ctx, text).await
+        } else {
+            self.send_message(text).await
+        }
+    }
+
+    /// Changes the nickname at most once per `NICKNAME_INTERVAL` to not trigger
+    /// the flood protection of the server, rapid changes are coalesced so the
+    /// latest name wins.
     async fn set_nickname(&mut self, name: String) -> anyhow::Result<()> {
+        if self.shared_connection {
+            return Ok(());
+        }
+
+        let wait = match self.last_nickname_update {
+            Some(last) => NICKNAME_INTERVAL
+                .checked_sub(last.elapsed())
+                .unwrap_or_default(),
+            None => Duration::from_secs(0),
+        };
+        if wait == Duration::from_secs(0) {
+            self.pending_nickname = None;
+            return self.apply_nickname(name).await;
+        }
+
+        // Only the first delayed change schedules an update, later ones replace the name
+        if self.pending_nickname.replace(name).is_none() {
+            if let Some(addr) = self.my_addr.clone() {
+                tokio::spawn(async move {
+                    tokio::time::sleep(wait).await;
+                    addr.send(FlushNickname).await
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn apply_nickname(&mut self, name: String) -> anyhow::Result<()> {
+        self.last_nickname_update = Some(Instant::now());
         info!(self.logger, "Setting TeamSpeak nickname"; "name" => &name);
 
         if let Some(ts) = &mut self.teamspeak {
@@ -216,6 +530,10 @@ impl MusicBot {
     }
 
     async fn set_description(&mut self, desc: String) {
+        if self.shared_connection {
+            return;
+        }
+
         info!(self.logger, "Setting TeamSpeak description"; "description" => &desc);
 
         if let Some(ts) = &mut self.teamspeak {
@@ -223,13 +541,50 @@ impl MusicBot {
         }
     }
 
+    async fn update_avatar(&mut self, thumbnail: Option<&str>) {
+        if !self.avatar_from_thumbnail || self.shared_connection {
+            return;
+        }
+
+        let cached = thumbnail.and_then(|url| self.art_cache.get_by_url(url));
+        let data = match cached {
+            Some(art) => Some(art.data.clone()),
+            None => thumbnail.and_then(decode_data_uri),
+        };
+
+        if let Some(ts) = &mut self.teamspeak {
+            let result = match data {
+                Some(data) => ts.set_avatar(&data).await,
+                None => ts.clear_avatar().await,
+            };
+
+            if let Err(e) = result {
+                warn!(self.logger, "Failed to update avatar"; "error" => %e);
+            }
+        }
+    }
+
     async fn on_text(&mut self, message: ChatMessage) -> anyhow::Result<()> {
+        // Never react to our own messages to avoid command loops
+        if Some(message.invoker.id) == self.my_id().await? {
+            return Ok(());
+        }
+
         let msg = message.text;
         if msg.starts_with('!') {
             let tokens = msg[1..].split_whitespace().collect::<Vec<_>>();
 
+            if let ["help", name] = tokens.as_slice() {
+                return self.send_message(command::command_help(name)).await;
+            }
+
             match Command::from_iter_safe(&tokens) {
-                Ok(args) => self.on_command(args, message.invoker).await?,
+                Ok(args) => {
+                    let ctx = CommandContext {
+                        invoker: message.invoker,
+                    };
+                    self.on_command(args, ctx).await?
+                }
                 Err(e) if e.kind == structopt::clap::ErrorKind::HelpDisplayed => {
                     self.send_message(format!("\n{}", e.message)).await?;
                 }
@@ -240,202 +595,998 @@ impl MusicBot {
         Ok(())
     }
 
-    async fn on_command(&mut self, command: Command, invoker: Invoker) -> anyhow::Result<()> {
-        debug!(self.logger, "User command: {:?}", command);
+    async fn on_command(&mut self, command: Command, ctx: CommandContext) -> anyhow::Result<()> {
+        debug!(self.logger, "User command: {:?}", command; "user" => ctx.user_key());
+        if self.queue_locked && command.modifies_queue() && !self.is_admin(&ctx.invoker) {
+            return self
+                .send_error(
+                    &ctx,
+                    String::from("The playlist is locked, only admins can change it"),
+                )
+                .await;
+        }
+
         match command {
             Command::Play => {
                 if !self.player.is_started() {
                     if !self.playlist.is_empty() {
                         self.player.stop_current()?;
+                    } else {
+                        self.send_error(
+                            &ctx,
+                            String::from("There is nothing to play, add something with !add"),
+                        )
+                        .await?;
                     }
                 } else {
                     self.player.play()?;
+                    self.send_feedback(String::from("Resumed")).await?;
                 }
             }
-            Command::Add { url } => {
-                // strip bbcode tags from url
-                let url = url.join(" ").replace("[URL]", "").replace("[/URL]", "");
+            Command::PlayNext { url } => {
+                let locations = audio_locations(ts::strip_links(&url.join(" ")));
+                self.add_next(&ctx, locations, None).await?;
+            }
+            Command::Add { top, mut url } => {
+                if top && !self.is_admin(&ctx.invoker) {
+                    return self
+                        .send_error(&ctx, String::from("Only admins can use --top"))
+                        .await;
+                }
 
-                let location = if url.starts_with(FILE_PREFIX) {
-                    AudioLocation::Path(PathBuf::from(&url[FILE_PREFIX.len()..]))
-                } else {
-                    AudioLocation::Url(url)
+                // A trailing range like 0:30-1:15 only plays that part
+                let clip = match url.last().map(|last| last.parse::<ClipRange>()) {
+                    Some(Ok(clip)) if url.len() > 1 => {
+                        url.pop();
+                        Some(clip)
+                    }
+                    Some(Err(e @ ClipRangeError::EndBeforeStart)) => {
+                        return self.send_error(&ctx, e.to_string()).await;
+                    }
+                    _ => None,
                 };
 
-                if let Err(e) = self.add_audio(location, invoker.name).await {
-                    self.send_message(format!("Failed to add: {}", e)).await?;
+                // strip bbcode tags from url
+                let locations = audio_locations(ts::strip_links(&url.join(" ")));
+
+                let user = ctx.invoker.name.clone();
+                if top {
+                    self.add_next(&ctx, locations, clip).await?;
+                } else if locations.len() > 1 {
+                    self.add_audio_bulk(locations, user, clip).await?;
+                } else if let Some(location) = locations.into_iter().next() {
+                    if let Err(e) = self.add_audio(location, user, clip).await {
+                        self.send_error(&ctx, format!("Failed to add: {}", e))
+                            .await?;
+                    }
                 }
             }
-            Command::Search { query } => {
-                let location = if let Some(path) = self.find_local_file(&query).await {
-                    AudioLocation::Path(path)
+            Command::Search { local, yt, query } => {
+                let local_file = if yt {
+                    None
                 } else {
-                    AudioLocation::YoutubeSearch(query.join(" "))
+                    self.find_local_file(&query).await
                 };
 
-                if let Err(e) = self.add_audio(location, invoker.name).await {
-                    self.send_message(format!("Failed to add: {}", e)).await?;
+                let location = match local_file {
+                    Some(path) => AudioLocation::Path(path),
+                    None if local => {
+                        self.send_error(&ctx, String::from("No matching local file found"))
+                            .await?;
+                        return Ok(());
+                    }
+                    None => AudioLocation::YoutubeSearch(query.join(" ")),
+                };
+
+                if let Err(e) = self
+                    .add_audio(location, ctx.invoker.name.clone(), None)
+                    .await
+                {
+                    self.send_error(&ctx, format!("Failed to add: {}", e))
+                        .await?;
                 }
             }
             Command::Pause => {
                 self.player.pause()?;
+                self.send_feedback(String::from("Paused")).await?;
             }
             Command::Stop => {
                 self.player.reset()?;
+                self.send_feedback(String::from("Stopped")).await?;
+            }
+            Command::Seek { amount } => {
+                if !self.player.is_started() {
+                    match self.last_track.clone() {
+                        // A finished or stopped track is started again to seek in it
+                        Some(track) => self.start_playing_audio(track).await?,
+                        None => {
+                            self.send_error(&ctx, String::from("There is nothing to seek"))
+                                .await?;
+                            return Ok(());
+                        }
+                    }
+                }
+
+                self.seek(amount, &ctx).await?;
             }
-            Command::Seek { amount } => match self.player.seek(amount) {
-                Ok(time) => {
-                    self.send_message(format!("New position: {}", ts::bold(&time)))
+            Command::Next { count } => {
+                let count = count.unwrap_or(1);
+                if count == 0 {
+                    self.send_error(&ctx, String::from("Can not skip 0 tracks"))
                         .await?;
+                    return Ok(());
                 }
-                Err(e) => {
-                    warn!(self.logger, "Failed to seek"; "error" => %e);
-                    self.send_message(String::from("Failed to seek")).await?;
+
+                // The current track is the first one that is skipped
+                let queued = (count - 1).min(self.playlist.len());
+                self.playlist.skip(queued);
+
+                if queued > 0 {
+                    let skipped = queued + self.player.is_started() as usize;
+                    self.send_message(format!("Skipped {} tracks", skipped))
+                        .await?;
+                } else if let Some(current) = self.player.currently_playing() {
+                    self.send_feedback(format!("Skipped {}", ts::underline(&current.title)))
+                        .await?;
                 }
-            },
-            Command::Next => {
+
                 if !self.playlist.is_empty() {
                     info!(self.logger, "Skipping to next track");
+                    self.skipping = true;
                     self.player.stop_current()?;
                 } else {
                     info!(self.logger, "Playlist empty, cannot skip");
                     self.player.reset()?;
                 }
             }
-            Command::Clear => {
-                self.send_message(String::from("Cleared playlist")).await?;
-                self.playlist.clear();
-            }
-            Command::Volume { volume } => {
-                self.player.change_volume(volume)?;
-                self.update_name(self.state()).await?;
-            }
-            Command::Leave => {
-                self.quit(String::from("Leaving"), true).await?;
-            }
-        }
-
-        Ok(())
-    }
+            Command::Track { number } => {
+                let total = self.playlist.len();
+                if number == 0 || number > total {
+                    self.send_error(
+                        &ctx,
+                        format!(
+                            "There is no track {}, the playlist has {} entries",
+                            number, total
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
 
-    pub async fn add_audio(&mut self, location: AudioLocation, user: String) -> anyhow::Result<()> {
-        let metadata = match location {
-            AudioLocation::Path(rel_path) => {
-                if self.music_root.is_none() {
-                    anyhow::bail!("music_root was not configured");
+                self.playlist.skip(number - 1);
+                let title = self
+                    .playlist
+                    .peek()
+                    .expect("playlist is not empty")
+                    .title
+                    .clone();
+                self.send_message(format!(
+                    "Track {}/{}: {}",
+                    number,
+                    total,
+                    ts::underline(&title)
+                ))
+                .await?;
+
+                if self.player.is_started() {
+                    self.skipping = true;
+                    self.player.stop_current()?;
+                } else if let Some(request) = self.playlist.pop() {
+                    self.start_playing_audio(request).await?;
                 }
+            }
+            Command::Favorite => self.save_favorite(&ctx).await?,
+            Command::Favorites => {
+                let favorites = match self.load_favorites(&ctx).await? {
+                    Some(favorites) => favorites,
+                    None => return Ok(()),
+                };
 
-                let path = self.music_root.as_ref().unwrap().join(rel_path);
-                let path = match path.canonicalize() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        return Err(anyhow!("Invalid path: {}", e)).into();
+                let text = if favorites.is_empty() {
+                    String::from("You have no favorites yet, save the current track with !favorite")
+                } else {
+                    let mut text = String::from("Your favorites:");
+                    for (i, favorite) in favorites.iter().enumerate() {
+                        text.push_str(&format!("\n{}. {}", i + 1, ts::underline(&favorite.title)));
                     }
+                    text
                 };
-
-                // Make sure files outside of the root path can't be accessed
-                if !path.starts_with(self.music_root.as_ref().unwrap()) || !path.is_file() {
-                    return Err(anyhow!("Invalid path"));
-                }
-
-                match metadata_from_file(&path, &user) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        warn!(
-                            self.logger,
-                            "No metadata was found for {}: {}",
-                            path.to_string_lossy(),
-                            e
+                self.reply_to_user(&ctx, text).await?;
+            }
+            Command::PlayFav { number } => {
+                let favorites = match self.load_favorites(&ctx).await? {
+                    Some(favorites) => favorites,
+                    None => return Ok(()),
+                };
+                let favorite = match number.checked_sub(1).and_then(|i| favorites.get(i)) {
+                    Some(favorite) => favorite,
+                    None => {
+                        let msg = format!(
+                            "There is no favorite {}, you have {} favorites",
+                            number,
+                            favorites.len()
                         );
-
-                        AudioMetadata {
-                            // FIXME: Since we use a rust string non-utf8 file names
-                            // will not work as expected
-                            uri: format!("{}{}", FILE_PREFIX, path.to_string_lossy()),
-                            webpage_url: None,
-                            title: path.file_name().unwrap().to_string_lossy().to_string(),
-                            thumbnail: None,
-                            duration: None,
-                            added_by: user,
-                        }
+                        return self.send_error(&ctx, msg).await;
                     }
+                };
+
+                let location = self.favorite_location(&favorite.url);
+                let user = ctx.invoker.name.clone();
+                if let Err(e) = self.add_audio(location, user, None).await {
+                    self.send_error(&ctx, format!("Failed to add: {}", e))
+                        .await?;
                 }
             }
-            AudioLocation::YoutubeSearch(query) => {
-                self.get_url_from_ytdl(format!("ytsearch:{}", query), user)
-                    .await?
+            Command::Move { from, to } => {
+                let len = self.playlist.len();
+                let msg = match self.playlist.move_entry(from, to) {
+                    Some(track) => format!("Moved {} to {}", ts::underline(&track.title), to),
+                    None if len == 0 => String::from("The playlist is empty"),
+                    None => format!(
+                        "Positions have to be between 1 and {}, the length of the playlist",
+                        len
+                    ),
+                };
+                self.send_message(msg).await?;
             }
-            AudioLocation::Url(query) => self.get_url_from_ytdl(query, user).await?,
-        };
-
-        self.playlist.push(metadata.clone());
-
-        if !self.player.is_started() {
-            if let Some(request) = self.playlist.pop() {
-                self.start_playing_audio(request).await?;
+            Command::Remove { index } => match self.playlist.remove(index) {
+                Some(track) => {
+                    self.send_message(format!("Removed {}", ts::underline(&track.title)))
+                        .await?;
+                }
+                None => {
+                    let msg = format!(
+                        "Index {} is out of range, the playlist has {} entries",
+                        index,
+                        self.playlist.len()
+                    );
+                    self.send_error(&ctx, msg).await?;
+                }
+            },
+            Command::Shuffle => {
+                self.playlist.shuffle(&mut self.rng);
+                self.send_message(format!("Shuffled {} tracks", self.playlist.len()))
+                    .await?;
             }
-        } else {
-            let duration = if let Some(duration) = metadata.duration {
-                format!(" ({})", ts::bold(&humantime::format_duration(duration)))
-            } else {
-                format!("")
-            };
-
-            let msg = if metadata.uri.starts_with(FILE_PREFIX) {
-                format!(
-                    "Added local file {}{} to playlist",
-                    ts::underline(&metadata.title),
-                    duration
-                )
-            } else {
-                format!(
-                    "Added {}{} to playlist",
-                    ts::underline(&metadata.title),
-                    duration
-                )
-            };
-
-            if let Err(e) = self.send_message(msg).await {
-                error!(self.logger, "Failed to send message: {}", e);
+            Command::Sort { by } => {
+                self.playlist.sort_by(|a, b| compare_tracks(by, a, b));
+                self.send_message(format!("Sorted {} tracks", self.playlist.len()))
+                    .await?;
             }
-        }
+            Command::Repeat { mode } => {
+                self.repeat = mode;
+                self.update_name(self.state).await?;
+                let msg = match mode {
+                    RepeatMode::Off => "Repeat is off",
+                    RepeatMode::One => "Repeating the current track",
+                    RepeatMode::All => "Repeating the playlist",
+                };
+                self.send_message(String::from(msg)).await?;
+            }
+            Command::Eta => {
+                let msg = self.eta_text();
+                self.send_message(msg).await?;
+            }
+            Command::Queue { by } => {
+                let mut text = queue_text(self.playlist.to_vec(), by);
+                if !self.playlist.is_empty() {
+                    text.push('\n');
+                    text.push_str(&self.eta_text());
+                }
+                self.send_message(text).await?;
+            }
+            Command::NowPlaying { target } => {
+                let text = match self.player.currently_playing() {
+                    Some(track) => self.now_playing_text(&track),
+                    None => String::from("Nothing is playing"),
+                };
 
-        Ok(())
-    }
+                if let Some("me") | Some("@me") = target.as_deref() {
+                    self.reply_to_user(&ctx, text).await?;
+                } else {
+                    self.send_message(text).await?;
+                }
+            }
+            Command::Requester => {
+                let text = match self.player.currently_playing() {
+                    // Imported tracks and older saved playlists have no requester
+                    Some(track) if track.added_by.is_empty() => {
+                        String::from("It is unknown who added the current track")
+                    }
+                    Some(track) => format!("Current track was added by {}", track.added_by),
+                    None => String::from("Nothing is playing"),
+                };
+                self.send_message(text).await?;
+            }
+            Command::Channel => {
+                let ts = match &mut self.teamspeak {
+                    Some(ts) => ts,
+                    None => {
+                        self.send_error(&ctx, String::from("Not connected to TeamSpeak"))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+                let path = ts.my_channel_path().await?;
+                let channel = ts.current_channel().await?;
+
+                let (path, channel) = match (path, channel) {
+                    (Some(path), Some(channel)) => (path, channel),
+                    _ => {
+                        self.send_error(&ctx, String::from("Failed to find the channel"))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+                // The bot itself is not listening
+                let listeners = self.user_count(channel).await?.saturating_sub(1);
+
+                self.send_message(format!(
+                    "In channel {} with {} listeners",
+                    ts::bold(&path),
+                    listeners
+                ))
+                .await?;
+            }
+            Command::Rename { name } => {
+                if !self.is_admin(&ctx.invoker) {
+                    self.send_error(&ctx, String::from("Only admins can rename the bot"))
+                        .await?;
+                    return Ok(());
+                }
 
-    async fn get_url_from_ytdl(
-        &self,
-        query: String,
-        user: String,
-    ) -> anyhow::Result<AudioMetadata> {
-        match crate::youtube_dl::get_audio_download_from_url(query, &self.logger).await {
-            Ok(mut metadata) => {
-                metadata.added_by = user;
-                info!(self.logger, "Found source"; "uri" => &metadata.uri);
+                let name = name.join(" ");
+                let length = name.chars().count();
+                if length > MAX_NICKNAME_LENGTH {
+                    let msg = format!(
+                        "Names can be at most {} characters long",
+                        MAX_NICKNAME_LENGTH
+                    );
+                    self.send_error(&ctx, msg).await?;
+                    return Ok(());
+                }
 
-                Ok(metadata)
+                self.display_name = name;
+                self.update_name(self.state).await?;
+                self.send_feedback(format!("Renamed to {}", self.display_name))
+                    .await?;
             }
-            Err(e) => {
-                info!(self.logger, "Failed to find audio url"; "error" => &e);
+            Command::YtdlVersion => {
+                if !self.is_admin(&ctx.invoker) {
+                    self.send_error(&ctx, String::from("Only admins can check youtube-dl"))
+                        .await?;
+                    return Ok(());
+                }
 
-                Err(anyhow!("Failed to find url: {}", e)).into()
+                match crate::youtube_dl::version().await {
+                    Ok(version) => {
+                        self.send_message(format!("youtube-dl version {}", ts::bold(&version)))
+                            .await?;
+                    }
+                    Err(e) => {
+                        self.send_error(&ctx, format!("youtube-dl does not work: {}", e))
+                            .await?;
+                    }
+                }
             }
-        }
-    }
+            Command::Ping => {
+                let start = Instant::now();
+                // Asking the connection for our id waits for the TeamSpeak event loop
+                if let Some(ts) = &mut self.teamspeak {
+                    ts.my_id().await?;
+                }
+                let latency = start.elapsed();
 
-    async fn start_playing_audio(&mut self, metadata: AudioMetadata) -> anyhow::Result<()> {
-        let duration = if let Some(duration) = metadata.duration {
-            format!("({})", ts::bold(&humantime::format_duration(duration)))
-        } else {
-            format!("")
-        };
+                self.send_message(format!(
+                    "Pong! Responded in {}",
+                    ts::bold(&format!("{:.1} ms", latency.as_secs_f64() * 1000.0))
+                ))
+                .await?;
+            }
+            Command::Clear => {
+                let tracks = self.playlist.to_vec();
+                self.playlist.clear();
 
-        let msg = if metadata.uri.starts_with(FILE_PREFIX) {
-            format!(
-                "Playing local file {} {}",
-                ts::underline(&metadata.title),
-                duration
-            )
+                let msg = if tracks.is_empty() {
+                    String::from("Playlist was already empty")
+                } else {
+                    let count = tracks.len();
+                    self.undo = Some(UndoSnapshot {
+                        tracks,
+                        taken: Instant::now(),
+                    });
+
+                    format!(
+                        "Cleared {} entries from the playlist, use {} to get them back",
+                        count,
+                        ts::bold("!undo")
+                    )
+                };
+                self.send_message(msg).await?;
+            }
+            Command::Undo => match self.undo.take() {
+                Some(snapshot) if snapshot.taken.elapsed() <= UNDO_WINDOW => {
+                    let count = snapshot.tracks.len();
+                    // Tracks added since then stay queued behind the restored ones
+                    let mut tracks = snapshot.tracks;
+                    tracks.extend(self.playlist.drain_all());
+                    self.remove_blocked(&mut tracks);
+                    let dropped = self.playlist.replace(tracks).len();
+
+                    self.send_message(format!("Restored {} entries to the playlist", count))
+                        .await?;
+                    self.report_dropped(dropped).await?;
+                }
+                _ => {
+                    self.send_error(&ctx, String::from("There is nothing to undo"))
+                        .await?;
+                }
+            },
+            Command::Dedup => {
+                let msg = match self.playlist.dedup() {
+                    0 => String::from("There are no duplicates in the playlist"),
+                    removed => format!("Removed {} duplicates from the playlist", removed),
+                };
+                self.send_message(msg).await?;
+            }
+            Command::Volume { volume } => {
+                self.set_volume(volume).await?;
+            }
+            Command::Mute => {
+                if self.muted_volume.is_some() {
+                    self.send_error(&ctx, String::from("The bot is already muted"))
+                        .await?;
+                } else {
+                    self.muted_volume = Some(self.player.volume());
+                    self.apply_volume(VolumeChange::Absolute(0.0)).await?;
+                }
+            }
+            Command::Unmute => match self.muted_volume.take() {
+                Some(volume) => self.apply_volume(VolumeChange::Absolute(volume)).await?,
+                None => {
+                    self.send_error(&ctx, String::from("The bot is not muted"))
+                        .await?;
+                }
+            },
+            Command::Leave => {
+                match self.save_queue() {
+                    Ok(Some((name, count))) => {
+                        self.send_message(format!(
+                            "Saved {} tracks, use {} to get them back",
+                            count,
+                            ts::bold(&format!("!restore {}", name))
+                        ))
+                        .await?;
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        warn!(self.logger, "Failed to save queue"; "error" => %e);
+                        self.send_error(&ctx, String::from("Failed to save the playlist"))
+                            .await?;
+                    }
+                }
+
+                self.quit(String::from("Leaving"), true).await?;
+            }
+            Command::Restore { name } => match self.load_queue(&name) {
+                Ok(mut tracks) => {
+                    // Playlists saved by older versions do not know who added the tracks
+                    for track in tracks.iter_mut().filter(|t| t.added_by.is_empty()) {
+                        track.added_by = ctx.invoker.name.clone();
+                    }
+
+                    let blocked = self.remove_blocked(&mut tracks);
+                    let count = tracks.len();
+                    let dropped = self.playlist.extend(tracks).len();
+                    self.send_message(format!("Restored {} tracks", count - dropped))
+                        .await?;
+                    self.report_blocked(blocked).await?;
+                    self.report_dropped(dropped).await?;
+
+                    if !self.player.is_started() {
+                        self.player.stop_current()?;
+                    }
+                }
+                Err(e) => {
+                    self.send_error(&ctx, format!("Failed to restore: {}", e))
+                        .await?;
+                }
+            },
+            Command::Lock => self.set_queue_locked(&ctx, true).await?,
+            Command::Unlock => self.set_queue_locked(&ctx, false).await?,
+            Command::Transfer { bot } => {
+                if !self.is_admin(&ctx.invoker) {
+                    self.send_error(&ctx, String::from("Only admins can transfer the playlist"))
+                        .await?;
+                    return Ok(());
+                }
+
+                match &self.master {
+                    // The master needs to talk to this bot to take the playlist,
+                    // so the request must not be awaited while handling this command.
+                    Some(master) => {
+                        tokio::spawn(master.send(TransferPlaylist {
+                            from: self.name.clone(),
+                            to: bot.join(" "),
+                            invoker: ctx.invoker.id,
+                        }));
+                    }
+                    None => {
+                        self.send_error(
+                            &ctx,
+                            String::from("There are no other bots to transfer to"),
+                        )
+                        .await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn seek(&mut self, amount: Seek, ctx: &CommandContext) -> anyhow::Result<()> {
+        match self.player.seek(amount) {
+            Ok(time) => {
+                self.send_message(format!("New position: {}", ts::bold(&time)))
+                    .await
+            }
+            Err(AudioPlayerError::UnknownDuration) => {
+                self.send_error(
+                    ctx,
+                    String::from("The length of this track is unknown, seek by time instead"),
+                )
+                .await
+            }
+            Err(e) => {
+                warn!(self.logger, "Failed to seek"; "error" => %e);
+                self.send_error(ctx, String::from("Failed to seek")).await
+            }
+        }
+    }
+
+    async fn set_queue_locked(&mut self, ctx: &CommandContext, locked: bool) -> anyhow::Result<()> {
+        if !self.is_admin(&ctx.invoker) {
+            return self
+                .send_error(ctx, String::from("Only admins can lock the playlist"))
+                .await;
+        }
+
+        self.queue_locked = locked;
+        let msg = if locked {
+            "The playlist is locked, only admins can change it now"
+        } else {
+            "The playlist is unlocked"
+        };
+
+        self.send_message(String::from(msg)).await
+    }
+
+    /// Changing the volume explicitly also ends a mute.
+    async fn set_volume(&mut self, volume: VolumeChange) -> anyhow::Result<()> {
+        self.muted_volume = None;
+        self.apply_volume(volume).await
+    }
+
+    async fn apply_volume(&mut self, volume: VolumeChange) -> anyhow::Result<()> {
+        let volume = match volume {
+            VolumeChange::Reset => VolumeChange::Absolute(self.default_volume),
+            volume => volume,
+        };
+        self.player.change_volume(volume)?;
+        // Fails if no web client is listening, which is fine
+        let _ = self.volume_updates.send(self.player.volume());
+        self.update_name(self.state()).await
+    }
+
+    pub async fn add_audio(
+        &mut self,
+        location: AudioLocation,
+        user: String,
+        clip: Option<ClipRange>,
+    ) -> anyhow::Result<()> {
+        let tracks = self.resolve_audio(location, user, clip).await?;
+        let metadata = tracks[0].clone();
+        let mut added = 0;
+        for track in tracks {
+            if let Err(e) = self.playlist.push(track) {
+                self.send_message(e.to_string()).await?;
+                break;
+            }
+            added += 1;
+        }
+        if added == 0 {
+            return Ok(());
+        }
+        let extra_tracks = added - 1;
+
+        if extra_tracks > 0 {
+            self.send_message(format!(
+                "Also added {} more tracks of the playlist",
+                extra_tracks
+            ))
+            .await?;
+        }
+
+        if !self.player.is_started() {
+            if let Some(request) = self.playlist.pop() {
+                self.start_playing_audio(request).await?;
+            }
+        } else {
+            let duration = if let Some(duration) = metadata.duration {
+                format!(" ({})", ts::bold(&humantime::format_duration(duration)))
+            } else {
+                format!("")
+            };
+
+            let msg = if metadata.uri.starts_with(FILE_PREFIX) {
+                format!(
+                    "Added local file {}{} to playlist",
+                    ts::underline(&metadata.title),
+                    duration
+                )
+            } else {
+                format!(
+                    "Added {}{} to playlist",
+                    ts::underline(&metadata.title),
+                    duration
+                )
+            };
+
+            if let Err(e) = self.send_message(msg).await {
+                error!(self.logger, "Failed to send message: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the tracks that match the blocklist, returns how many were removed.
+    fn remove_blocked(&self, tracks: &mut Vec<AudioMetadata>) -> usize {
+        let before = tracks.len();
+        tracks.retain(|track| self.blocklist.blocked_by(track).is_none());
+
+        let removed = before - tracks.len();
+        if removed > 0 {
+            info!(self.logger, "Left out blocked tracks"; "count" => removed);
+        }
+
+        removed
+    }
+
+    async fn report_blocked(&mut self, blocked: usize) -> anyhow::Result<()> {
+        if blocked == 0 {
+            return Ok(());
+        }
+
+        self.send_message(format!(
+            "Left out {} tracks that are not allowed here",
+            blocked
+        ))
+        .await
+    }
+
+    /// Tells the channel how many tracks were left out because the playlist is full.
+    async fn report_dropped(&mut self, dropped: usize) -> anyhow::Result<()> {
+        match self.playlist.remaining() {
+            Some((_, max_len)) if dropped > 0 => {
+                self.send_message(format!(
+                    "{}, left out {} tracks",
+                    PlaylistFull(max_len),
+                    dropped
+                ))
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn on_playlist_received(
+        &mut self,
+        from: &str,
+        added: usize,
+        blocked: usize,
+        dropped: usize,
+    ) -> anyhow::Result<()> {
+        self.cancel_leave();
+        self.send_message(format!(
+            "Received {} tracks from {}",
+            ts::bold(&added),
+            ts::underline(from)
+        ))
+        .await?;
+        self.report_blocked(blocked).await?;
+        self.report_dropped(dropped).await?;
+
+        if !self.player.is_started() {
+            if let Some(request) = self.playlist.pop() {
+                self.start_playing_audio(request).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a single location in front of the queue for `!playnext` and `!add --top`.
+    async fn add_next(
+        &mut self,
+        ctx: &CommandContext,
+        locations: Vec<AudioLocation>,
+        clip: Option<ClipRange>,
+    ) -> anyhow::Result<()> {
+        if locations.len() > 1 {
+            return self
+                .send_error(ctx, String::from("Only one url can be played next"))
+                .await;
+        }
+
+        if let Some(location) = locations.into_iter().next() {
+            let user = ctx.invoker.name.clone();
+            if let Err(e) = self.add_audio_next(location, user, clip).await {
+                self.send_error(ctx, format!("Failed to add: {}", e))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the tracks in front of the queue, playlists keep their order.
+    async fn add_audio_next(
+        &mut self,
+        location: AudioLocation,
+        user: String,
+        clip: Option<ClipRange>,
+    ) -> anyhow::Result<()> {
+        let mut tracks = self.resolve_audio(location, user, clip).await?;
+        let title = tracks[0].title.clone();
+        // Only the start of a playlist is added if it does not fit
+        if let Some((remaining, max_len)) = self.playlist.remaining() {
+            if remaining < tracks.len() {
+                self.send_message(PlaylistFull(max_len).to_string()).await?;
+                if remaining == 0 {
+                    return Ok(());
+                }
+                tracks.truncate(remaining);
+            }
+        }
+        for track in tracks.into_iter().rev() {
+            self.playlist.push_next(track)?;
+        }
+
+        if !self.player.is_started() {
+            if let Some(request) = self.playlist.pop() {
+                self.start_playing_audio(request).await?;
+            }
+        } else {
+            self.send_message(format!("{} will play next", ts::underline(&title)))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds every location and reports the outcome in a single message
+    /// instead of one message per track.
+    async fn add_audio_bulk(
+        &mut self,
+        locations: Vec<AudioLocation>,
+        user: String,
+        clip: Option<ClipRange>,
+    ) -> anyhow::Result<()> {
+        let mut results = BulkResults::new();
+        for location in locations {
+            match self.resolve_audio(location, user.clone(), clip).await {
+                Ok(tracks) => {
+                    for track in tracks {
+                        match self.playlist.push(track) {
+                            Ok(()) => results.queued(1),
+                            Err(e) => {
+                                results.failed(e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => results.failed(e),
+            }
+        }
+
+        if !self.player.is_started() {
+            if let Some(request) = self.playlist.pop() {
+                self.start_playing_audio(request).await?;
+            }
+        }
+
+        self.send_message(results.summary()).await
+    }
+
+    /// Looks up the tracks of a location, the first one is the requested track
+    /// and the others are the rest of a playlist the link expanded to.
+    async fn resolve_audio(
+        &mut self,
+        location: AudioLocation,
+        user: String,
+        clip: Option<ClipRange>,
+    ) -> anyhow::Result<Vec<AudioMetadata>> {
+        // Adding tracks counts as activity even if nobody is in the channel
+        self.cancel_leave();
+
+        match &location {
+            AudioLocation::Url(url) | AudioLocation::YoutubeSearch(url)
+                if url.trim().is_empty() =>
+            {
+                anyhow::bail!("Please provide a URL or search term");
+            }
+            AudioLocation::Url(url) if !looks_like_url(url) => {
+                anyhow::bail!("This is not a URL, use !search to search for it");
+            }
+            _ => (),
+        }
+
+        let mut rest_of_playlist = Vec::new();
+        let mut metadata = match location {
+            AudioLocation::Path(rel_path) => {
+                if self.music_root.is_none() {
+                    anyhow::bail!("music_root was not configured");
+                }
+
+                let path = self.music_root.as_ref().unwrap().join(&rel_path);
+                let path = match path.canonicalize() {
+                    Ok(p) => p,
+                    // Only the relative path is shown to not reveal the music root
+                    Err(e) => {
+                        return Err(match e.kind() {
+                            io::ErrorKind::NotFound => {
+                                anyhow!("File not found: {}", rel_path.display())
+                            }
+                            io::ErrorKind::PermissionDenied => {
+                                anyhow!("Permission denied: {}", rel_path.display())
+                            }
+                            _ => anyhow!("Invalid path: {}", e),
+                        });
+                    }
+                };
+
+                // Make sure files outside of the root path can't be accessed
+                if !path.starts_with(self.music_root.as_ref().unwrap()) || !path.is_file() {
+                    return Err(anyhow!("Invalid path"));
+                }
+
+                match metadata_from_file(&path, &user, &self.art_cache) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!(
+                            self.logger,
+                            "No metadata was found for {}: {}",
+                            path.to_string_lossy(),
+                            e
+                        );
+
+                        AudioMetadata {
+                            // FIXME: Since we use a rust string non-utf8 file names
+                            // will not work as expected
+                            uri: format!("{}{}", FILE_PREFIX, path.to_string_lossy()),
+                            webpage_url: None,
+                            title: path.file_name().unwrap().to_string_lossy().to_string(),
+                            thumbnail: None,
+                            duration: None,
+                            added_by: user,
+                            start_offset: None,
+                            end_offset: None,
+                        }
+                    }
+                }
+            }
+            AudioLocation::YoutubeSearch(query) => self
+                .get_url_from_ytdl(format!("ytsearch:{}", query), user)
+                .await?
+                .remove(0),
+            AudioLocation::Url(url) => {
+                match self.get_url_from_ytdl(url.clone(), user.clone()).await {
+                    Ok(mut tracks) => {
+                        let first = tracks.remove(0);
+                        if self.expand_playlists {
+                            rest_of_playlist = tracks;
+                        } else if !tracks.is_empty() {
+                            info!(
+                                self.logger,
+                                "Link expanded to a playlist, only using the first entry";
+                                "ignored" => tracks.len()
+                            );
+                        }
+
+                        first
+                    }
+                    Err(e) if self.search_on_url_failure => {
+                        let query = search_query_from_url(&url).ok_or(e)?;
+                        info!(self.logger, "Searching instead of url"; "query" => &query);
+
+                        let metadata = self
+                            .get_url_from_ytdl(format!("ytsearch:{}", query), user)
+                            .await?
+                            .remove(0);
+                        self.send_message(format!(
+                            "Failed to load the link, searched for {} instead",
+                            ts::bold(&query)
+                        ))
+                        .await?;
+
+                        metadata
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        if let Some(clip) = clip {
+            if let Some(duration) = metadata.duration {
+                if clip.end > duration {
+                    anyhow::bail!(
+                        "The clip ends after the track, which is only {} long",
+                        humantime::format_duration(duration)
+                    );
+                }
+            }
+
+            metadata.start_offset = Some(clip.start);
+            metadata.end_offset = Some(clip.end);
+        }
+
+        if let Some(entry) = self.blocklist.blocked_by(&metadata) {
+            info!(self.logger, "Refusing blocked track"; "title" => &metadata.title, "entry" => entry);
+            anyhow::bail!("{} is not allowed here", metadata.title);
+        }
+
+        self.remove_blocked(&mut rest_of_playlist);
+
+        let mut tracks = vec![metadata];
+        tracks.extend(rest_of_playlist);
+
+        Ok(tracks)
+    }
+
+    async fn get_url_from_ytdl(
+        &self,
+        query: String,
+        user: String,
+    ) -> anyhow::Result<Vec<AudioMetadata>> {
+        match crate::youtube_dl::get_audio_download_from_url(
+            query,
+            self.domain_cooldown,
+            &self.logger,
+        )
+        .await
+        {
+            Ok(mut tracks) => {
+                for metadata in &mut tracks {
+                    metadata.added_by = user.clone();
+                    info!(self.logger, "Found source"; "uri" => &metadata.uri);
+                }
+
+                Ok(tracks)
+            }
+            Err(e) => {
+                info!(self.logger, "Failed to find audio url"; "error" => &e);
+
+                Err(anyhow!("Failed to find url: {}", e)).into()
+            }
+        }
+    }
+
+    async fn start_playing_audio(&mut self, metadata: AudioMetadata) -> anyhow::Result<()> {
+        let duration = if let Some(duration) = metadata.duration {
+            format!("({})", ts::bold(&humantime::format_duration(duration)))
+        } else {
+            format!("")
+        };
+
+        let msg = if metadata.uri.starts_with(FILE_PREFIX) {
+            format!(
+                "Playing local file {} {}",
+                ts::underline(&metadata.title),
+                duration
+            )
         } else {
             format!("Playing {} {}", ts::underline(&metadata.title), duration)
         };
@@ -443,6 +1594,10 @@ impl MusicBot {
         self.send_message(msg).await?;
         self.set_description(format!("Currently playing '{}'", metadata.title))
             .await;
+        self.update_avatar(metadata.thumbnail.as_deref()).await;
+        self.report_track_started(&metadata).await;
+        self.announced_next = false;
+        self.last_track = Some(metadata.clone());
         self.player.reset().unwrap();
         self.player.set_metadata(metadata).unwrap();
         self.player.play().unwrap();
@@ -450,6 +1605,180 @@ impl MusicBot {
         Ok(())
     }
 
+    fn start_position_ticker(&self, addr: WeakAddress<Self>) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(POSITION_TICK_INTERVAL).await;
+                // Stops once the bot is gone
+                if addr.send(PositionTick).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn log_heartbeat(&mut self) {
+        if self.last_heartbeat.elapsed() < HEARTBEAT_INTERVAL {
+            return;
+        }
+        self.last_heartbeat = Instant::now();
+
+        debug!(
+            self.logger,
+            "Heartbeat";
+            "state" => %self.state,
+            "position" => ?self.player.position(),
+            "queued" => self.playlist.len()
+        );
+    }
+
+    /// Skips the current track if its position did not advance for the stall
+    /// timeout, pauses and buffering do not count.
+    fn skip_stalled_track(&mut self) -> anyhow::Result<()> {
+        if self.stall_timeout == Duration::from_secs(0)
+            || self.state != State::Playing
+            || !self.player.is_playing()
+            || self.player.is_buffering()
+        {
+            self.last_progress = None;
+            return Ok(());
+        }
+
+        let position = match self.player.position() {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        match self.last_progress {
+            Some((last, since)) if last == position => {
+                if since.elapsed() >= self.stall_timeout {
+                    warn!(
+                        self.logger,
+                        "Playback is stuck, skipping track";
+                        "position" => ?position
+                    );
+                    self.last_progress = None;
+                    self.skipping = true;
+                    self.player.stop_current()?;
+                }
+            }
+            _ => self.last_progress = Some((position, Instant::now())),
+        }
+
+        Ok(())
+    }
+
+    /// Posts the title of the next track once the current one is about to end.
+    async fn announce_next_track(&mut self) -> anyhow::Result<()> {
+        if self.announced_next || self.state != State::Playing {
+            return Ok(());
+        }
+
+        let end = match self.player.currently_playing() {
+            Some(track) => match track.end_offset.or(track.duration) {
+                Some(end) => end,
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let position = match self.player.position() {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        if end.saturating_sub(position) > self.announce_next {
+            return Ok(());
+        }
+
+        // Something might still be added before the track ends
+        let msg = match self.playlist.peek() {
+            Some(next) => format!("Up next: {}", ts::underline(&next.title)),
+            None => return Ok(()),
+        };
+
+        self.announced_next = true;
+        self.send_message(msg).await
+    }
+
+    /// Remaining time of the current track and all queued ones together with
+    /// the number of tracks of unknown length, `None` if nothing is left.
+    fn remaining_time(&self) -> Option<(Duration, usize)> {
+        let queued = self.playlist.total_duration();
+        let current = self.player.currently_playing();
+        if current.is_none() && queued.is_none() {
+            return None;
+        }
+
+        let mut total = queued.unwrap_or_default();
+        let mut unknown = self.playlist.unknown_durations();
+
+        if let Some(track) = current {
+            match (track.end_offset.or(track.duration), self.player.position()) {
+                (Some(end), Some(position)) => total += end.saturating_sub(position),
+                (Some(end), None) => total += end,
+                (None, _) => unknown += 1,
+            }
+        }
+
+        Some((total, unknown))
+    }
+
+    fn eta_text(&self) -> String {
+        let (total, unknown) = match self.remaining_time() {
+            Some(remaining) => remaining,
+            None => return String::from("The playlist is empty"),
+        };
+
+        let remaining = humantime::format_duration(Duration::from_secs(total.as_secs()));
+        match unknown {
+            0 => format!("The playlist ends in {}", ts::bold(&remaining.to_string())),
+            1 => format!(
+                "The playlist ends in more than {}, 1 track has an unknown length",
+                ts::bold(&remaining.to_string())
+            ),
+            n => format!(
+                "The playlist ends in more than {}, {} tracks have an unknown length",
+                ts::bold(&remaining.to_string()),
+                n
+            ),
+        }
+    }
+
+    fn now_playing_text(&self, track: &AudioMetadata) -> String {
+        let progress = format!(
+            "{} / {}",
+            clock_time(Some(self.player.position().unwrap_or_default())),
+            clock_time(track.duration)
+        );
+
+        format!(
+            "Now playing {} ({}), added by {}",
+            ts::underline(&track.title),
+            ts::bold(&progress),
+            track.added_by
+        )
+    }
+
+    /// Tells the master about the track for its list of recently played tracks.
+    async fn report_track_started(&mut self, metadata: &AudioMetadata) {
+        let master = match &self.master {
+            Some(master) => master.clone(),
+            None => return,
+        };
+
+        let mut channel = None;
+        if let Ok(Some(id)) = self.my_id().await {
+            if let Some(ts) = &mut self.teamspeak {
+                channel = ts.channel_path_of_user(id).await.unwrap_or(None);
+            }
+        }
+
+        // The master might be waiting for this bot, so do not wait for it
+        tokio::spawn(master.send(TrackStarted {
+            bot: self.name.clone(),
+            channel,
+            track: metadata.clone(),
+        }));
+    }
+
     async fn find_local_file(&self, query: &Vec<String>) -> Option<PathBuf> {
         let known_exts = [OsStr::new("mp3"), OsStr::new("flac")];
 
@@ -508,13 +1837,25 @@ impl MusicBot {
     }
 
     async fn update_name(&mut self, state: State) -> anyhow::Result<()> {
-        let volume = (self.volume().await * 100.0).round();
-        let name = match state {
-            State::EndOfStream => format!("🎵 {} ({}%)", self.name, volume),
-            _ => format!("🎵 {} - {} ({}%)", self.name, state, volume),
+        let volume = if self.muted_volume.is_some() {
+            String::from(" (muted)")
+        } else if self.show_volume_in_name {
+            format!(" ({}%)", (self.volume().await * 100.0).round())
+        } else {
+            String::new()
+        };
+        let repeat = match self.repeat {
+            RepeatMode::Off => "",
+            RepeatMode::One => " \u{1f502}",
+            RepeatMode::All => " \u{1f501}",
+        };
+        let suffix = match state {
+            State::EndOfStream => format!("{}{}", volume, repeat),
+            _ => format!(" - {}{}{}", self.state_labels.label(state), volume, repeat),
         };
 
-        self.set_nickname(name).await
+        self.set_nickname(nickname(&self.display_name, &suffix))
+            .await
     }
 
     async fn on_message(&mut self, message: MusicBotMessage) -> anyhow::Result<()> {
@@ -529,7 +1870,9 @@ impl MusicBot {
                 old_channel,
             } => {
                 self.on_client_left_channel(client, old_channel).await?;
+                self.on_client_joined().await?;
             }
+            MusicBotMessage::ClientAdded(_) => self.on_client_joined().await?,
             MusicBotMessage::ClientDisconnected { id, client } => {
                 let old_channel = client.channel;
                 self.on_client_left_channel(id, old_channel).await?;
@@ -540,65 +1883,350 @@ impl MusicBot {
             _ => (),
         }
 
-        Ok(())
+        Ok(())
+    }
+
+    async fn on_state(&mut self, new_state: State) -> anyhow::Result<()> {
+        if self.state != new_state {
+            match new_state {
+                State::EndOfStream => {
+                    // Stopped tracks are gone at this point and never repeated
+                    let finished = self.player.currently_playing();
+                    let skipped = std::mem::replace(&mut self.skipping, false);
+                    self.player.reset()?;
+
+                    let next_track = match (self.repeat, finished) {
+                        (RepeatMode::One, Some(track)) if !skipped => Some(track),
+                        // Popping first makes room for the finished track
+                        (RepeatMode::All, Some(track)) => match self.playlist.pop() {
+                            Some(next) => {
+                                if let Err(e) = self.playlist.push(track) {
+                                    warn!(self.logger, "Failed to repeat track"; "error" => %e);
+                                }
+                                Some(next)
+                            }
+                            None => Some(track),
+                        },
+                        _ => self.playlist.pop(),
+                    };
+                    if let Some(request) = next_track {
+                        info!(self.logger, "Advancing playlist");
+
+                        self.start_playing_audio(request).await?;
+                    } else {
+                        self.update_name(new_state).await?;
+                        self.set_description(String::new()).await;
+                        self.update_avatar(None).await;
+                    }
+                }
+                State::Stopped => {
+                    if self.state != State::EndOfStream {
+                        self.update_name(new_state).await?;
+                        self.set_description(String::new()).await;
+                        self.update_avatar(None).await;
+                    }
+                }
+                _ => self.update_name(new_state).await?,
+            }
+        }
+
+        if !(self.state == State::EndOfStream && new_state == State::Stopped) {
+            self.state = new_state;
+        }
+
+        Ok(())
+    }
+
+    async fn on_client_left_channel(
+        &mut self,
+        id: ClientId,
+        old_channel: ChannelId,
+    ) -> anyhow::Result<()> {
+        match self.my_id().await {
+            Ok(Some(my_id)) if my_id == id => return self.on_moved().await,
+            Ok(Some(_)) => (),
+            _ => return Ok(()),
+        };
+
+        let current_channel = self
+            .current_channel()
+            .await?
+            .expect("Current channel is known");
+        if old_channel == current_channel {
+            match self.user_count(current_channel).await {
+                Ok(count) if count <= 1 => self.schedule_leave().await?,
+                Err(e) => self.quit(format!("Error: {}", e), true).await?,
+                Ok(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Playback continues after the bot was moved, but like after someone
+    /// left, the bot leaves if nobody is in the new channel.
+    async fn on_moved(&mut self) -> anyhow::Result<()> {
+        let current_channel = match self.current_channel().await? {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        info!(self.logger, "Moved to another channel"; "channel" => ?current_channel);
+
+        match self.user_count(current_channel).await {
+            Ok(count) if count <= 1 => self.schedule_leave().await?,
+            Err(e) => self.quit(format!("Error: {}", e), true).await?,
+            // A warning about the old channel being empty no longer applies
+            Ok(_) => self.pending_leave = None,
+        }
+
+        Ok(())
+    }
+
+    /// Stays in the channel if someone joined while the bot was about to leave.
+    async fn on_client_joined(&mut self) -> anyhow::Result<()> {
+        if self.pending_leave.is_none() {
+            return Ok(());
+        }
+
+        let current_channel = match self.current_channel().await? {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        if self.user_count(current_channel).await? > 1 {
+            self.cancel_leave();
+        }
+
+        Ok(())
+    }
+
+    fn cancel_leave(&mut self) {
+        if self.pending_leave.take().is_some() {
+            info!(self.logger, "Cancelled leaving the channel");
+        }
+    }
+
+    /// Warns that the bot is about to leave the empty channel and leaves
+    /// once the warning time has passed and the channel is still empty.
+    async fn schedule_leave(&mut self) -> anyhow::Result<()> {
+        if self.leave_warning == Duration::from_secs(0) {
+            return self.quit(String::from("Channel is empty"), true).await;
+        }
+
+        if self.pending_leave.is_some() {
+            return Ok(());
+        }
+
+        self.leave_generation += 1;
+        let generation = self.leave_generation;
+        self.pending_leave = Some(generation);
+
+        self.send_message(format!(
+            "Leaving in {} unless someone joins the channel or adds a track",
+            ts::bold(&humantime::format_duration(self.leave_warning))
+        ))
+        .await?;
+
+        if let Some(addr) = self.my_addr.clone() {
+            let delay = self.leave_warning;
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                addr.send(LeaveTimeout(generation)).await
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn on_leave_timeout(&mut self, generation: u64) -> anyhow::Result<()> {
+        if self.pending_leave != Some(generation) {
+            return Ok(());
+        }
+        self.pending_leave = None;
+
+        let current_channel = match self.current_channel().await? {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+
+        match self.user_count(current_channel).await {
+            Ok(count) if count <= 1 => self.quit(String::from("Channel is empty"), true).await,
+            Err(e) => self.quit(format!("Error: {}", e), true).await,
+            Ok(_) => {
+                info!(self.logger, "Channel is no longer empty, staying");
+                Ok(())
+            }
+        }
+    }
+
+    /// Saves the current track as a favorite of the invoker.
+    async fn save_favorite(&mut self, ctx: &CommandContext) -> anyhow::Result<()> {
+        let track = match self.player.currently_playing() {
+            Some(track) => track,
+            None => {
+                return self
+                    .send_error(ctx, String::from("Nothing is playing"))
+                    .await
+            }
+        };
+        let favorites = match &self.favorites {
+            Some(favorites) => favorites,
+            None => {
+                return self
+                    .send_error(ctx, String::from("favorites_dir was not configured"))
+                    .await
+            }
+        };
+
+        let favorite = Favorite {
+            title: track.title.clone(),
+            url: track.webpage_url.unwrap_or(track.uri),
+        };
+        match favorites.add(&ctx.user_key(), favorite) {
+            Ok(number) => {
+                let msg = format!(
+                    "Saved {} as favorite {}",
+                    ts::underline(&track.title),
+                    number
+                );
+                self.reply_to_user(ctx, msg).await
+            }
+            Err(e) => {
+                error!(self.logger, "Failed to save favorite"; "error" => %e);
+                self.send_error(ctx, String::from("Failed to save the favorite"))
+                    .await
+            }
+        }
+    }
+
+    /// Loads the favorites of the invoker, errors are already reported if
+    /// `None` is returned.
+    async fn load_favorites(
+        &mut self,
+        ctx: &CommandContext,
+    ) -> anyhow::Result<Option<Vec<Favorite>>> {
+        let result = match &self.favorites {
+            Some(favorites) => favorites.load(&ctx.user_key()),
+            None => {
+                self.send_error(ctx, String::from("favorites_dir was not configured"))
+                    .await?;
+                return Ok(None);
+            }
+        };
+
+        match result {
+            Ok(favorites) => Ok(Some(favorites)),
+            Err(e) => {
+                error!(self.logger, "Failed to load favorites"; "error" => %e);
+                self.send_error(ctx, String::from("Failed to load your favorites"))
+                    .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Local files are saved with their absolute path, but are added relative
+    /// to the music root like with `!add`.
+    fn favorite_location(&self, url: &str) -> AudioLocation {
+        if let (Some(path), Some(root)) = (url.strip_prefix(FILE_PREFIX), &self.music_root) {
+            if let Ok(relative) = Path::new(path).strip_prefix(root) {
+                return AudioLocation::Path(relative.to_owned());
+            }
+        }
+
+        AudioLocation::Url(url.to_owned())
+    }
+
+    /// Writes the current track and the playlist to `path`, nothing is written
+    /// if both are empty. Returns how many tracks were written.
+    fn write_queue(&self, path: &Path) -> anyhow::Result<usize> {
+        let mut tracks: Vec<_> = self.player.currently_playing().into_iter().collect();
+        tracks.extend(self.playlist.to_vec());
+        if tracks.is_empty() {
+            return Ok(0);
+        }
+
+        write_tracks(path, &tracks)?;
+        info!(self.logger, "Saved queue"; "path" => %path.display(), "count" => tracks.len());
+
+        Ok(tracks.len())
+    }
+
+    /// Reads the tracks written by `write_queue`, `None` if the file does not exist.
+    fn read_queue(path: &Path) -> anyhow::Result<Option<Vec<AudioMetadata>>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Writes the current track and the playlist to `playlist_dir` if it is configured.
+    ///
+    /// Returns the name the queue was saved as and how many tracks it contains.
+    fn save_queue(&self) -> anyhow::Result<Option<(String, usize)>> {
+        if self.playlist_dir.is_none() {
+            return Ok(None);
+        }
+
+        let path = saved_queue_path(self.playlist_dir.as_deref(), &self.name)?;
+        match self.write_queue(&path)? {
+            0 => Ok(None),
+            count => Ok(Some((self.name.clone(), count))),
+        }
     }
 
-    async fn on_state(&mut self, new_state: State) -> anyhow::Result<()> {
-        if self.state != new_state {
-            match new_state {
-                State::EndOfStream => {
-                    self.player.reset()?;
-                    let next_track = self.playlist.pop();
-                    if let Some(request) = next_track {
-                        info!(self.logger, "Advancing playlist");
+    fn load_queue(&self, name: &str) -> anyhow::Result<Vec<AudioMetadata>> {
+        let path = saved_queue_path(self.playlist_dir.as_deref(), name)?;
 
-                        self.start_playing_audio(request).await?;
-                    } else {
-                        self.update_name(new_state).await?;
-                        self.set_description(String::new()).await;
-                    }
-                }
-                State::Stopped => {
-                    if self.state != State::EndOfStream {
-                        self.update_name(new_state).await?;
-                        self.set_description(String::new()).await;
-                    }
-                }
-                _ => self.update_name(new_state).await?,
-            }
-        }
+        Self::read_queue(&path)?.ok_or_else(|| anyhow!("No playlist was saved as {}", name))
+    }
 
-        if !(self.state == State::EndOfStream && new_state == State::Stopped) {
-            self.state = new_state;
+    fn state_path(&self) -> Option<PathBuf> {
+        self.state_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", self.name)))
+    }
+
+    /// Writes the current track and the playlist to `state_dir` so that a bot
+    /// with the same name continues with them after a restart.
+    fn save_state(&self) -> anyhow::Result<()> {
+        let path = match self.state_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // An old snapshot would otherwise come back if nothing is queued
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
         }
+        self.write_queue(&path)?;
 
         Ok(())
     }
 
-    async fn on_client_left_channel(
-        &mut self,
-        id: ClientId,
-        old_channel: ChannelId,
-    ) -> anyhow::Result<()> {
-        match self.teamspeak.as_mut().unwrap().my_id().await {
-            Ok(my_id) if my_id != id => (),
-            _ => return Ok(()),
+    /// Queues the tracks saved by `save_state`, the snapshot is removed so that
+    /// it is only restored once.
+    fn restore_state(&mut self) -> anyhow::Result<()> {
+        let path = match self.state_path() {
+            Some(path) => path,
+            None => return Ok(()),
         };
 
-        let current_channel = self
-            .current_channel()
-            .await?
-            .expect("Current channel is known");
-        if old_channel == current_channel {
-            let quit = match self.user_count(current_channel).await {
-                Ok(count) if count <= 1 => Some(String::from("Channel is empty")),
-                Err(e) => Some(format!("Error: {}", e)),
-                Ok(_) => None,
-            };
+        let mut tracks = match Self::read_queue(&path)? {
+            Some(tracks) => tracks,
+            None => return Ok(()),
+        };
+        std::fs::remove_file(&path)?;
 
-            if let Some(reason) = quit {
-                self.quit(reason, true).await?;
-            }
+        info!(self.logger, "Restored state"; "path" => %path.display(), "count" => tracks.len());
+        self.remove_blocked(&mut tracks);
+        let dropped = self.playlist.replace(tracks).len();
+        if dropped > 0 {
+            warn!(self.logger, "Restored queue did not fit"; "dropped" => dropped);
         }
 
         Ok(())
@@ -609,6 +2237,16 @@ impl MusicBot {
         // change its name and description
         self.player.reset().unwrap();
 
+        // The master stays connected, playing over its connection just stops
+        if self.shared_connection {
+            if inform_master {
+                if let Some(master) = &self.master {
+                    master.send(PlayerStopped).await?;
+                }
+            }
+            return Ok(());
+        }
+
         if let Some(ts) = self.teamspeak.as_mut() {
             ts.disconnect(&reason).await?;
         }
@@ -633,25 +2271,64 @@ impl MusicBot {
 impl Actor for MusicBot {
     async fn started(&mut self, ctx: &mut Context<Self>) {
         let addr = ctx.address().unwrap().downgrade();
+        self.my_addr = Some(addr.clone());
+        self.start_position_ticker(addr.clone());
         self.player.register_bot(addr);
     }
 }
 
+pub struct FlushNickname;
+impl Message for FlushNickname {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<FlushNickname> for MusicBot {
+    async fn handle(&mut self, _: FlushNickname, _: &mut Context<Self>) -> anyhow::Result<()> {
+        match self.pending_nickname.take() {
+            Some(name) => self.apply_nickname(name).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct PositionTick;
+impl Message for PositionTick {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<PositionTick> for MusicBot {
+    async fn handle(&mut self, _: PositionTick, _: &mut Context<Self>) -> anyhow::Result<()> {
+        self.log_heartbeat();
+        self.skip_stalled_track()?;
+        self.announce_next_track().await
+    }
+}
+
+pub struct LeaveTimeout(u64);
+impl Message for LeaveTimeout {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<LeaveTimeout> for MusicBot {
+    async fn handle(&mut self, t: LeaveTimeout, _: &mut Context<Self>) -> anyhow::Result<()> {
+        self.on_leave_timeout(t.0).await
+    }
+}
+
 #[async_trait]
 impl Handler<Connect> for MusicBot {
     async fn handle(&mut self, opt: Connect, ctx: &mut Context<Self>) -> anyhow::Result<()> {
         let addr = ctx.address().unwrap().downgrade();
         if let Some(ts) = self.teamspeak.as_mut() {
-            ts.connect_for_bot(opt.0, addr)?;
-            let mut connection = ts.clone();
-            let handle = tokio::runtime::Handle::current();
-            self.player
-                .setup_with_audio_callback(Some(Box::new(move |samples| {
-                    handle
-                        .block_on(connection.send_audio_packet(samples))
-                        .unwrap();
-                })))
-                .unwrap();
+            ts.connect_for_bot(opt.0, addr).await?;
+            self.player.setup_with_audio_callback(Some(audio_callback(
+                ts.clone(),
+                self.codec,
+                self.logger.clone(),
+            )))?;
         }
 
         Ok(())
@@ -681,14 +2358,168 @@ impl Handler<GetBotData> for MusicBot {
         crate::web_server::BotData {
             name: self.name.clone(),
             playlist: self.playlist.to_vec(),
+            playlist_version: self.playlist.version(),
             currently_playing: self.player.currently_playing(),
             position: self.player.position(),
+            remaining: self.remaining_time().map(|(total, _)| total),
             state: self.state(),
             volume: self.volume().await,
         }
     }
 }
 
+pub struct ExecuteCommand(pub Command);
+impl Message for ExecuteCommand {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<ExecuteCommand> for MusicBot {
+    async fn handle(&mut self, cmd: ExecuteCommand, _: &mut Context<Self>) -> anyhow::Result<()> {
+        self.on_command(cmd.0, CommandContext::web()).await
+    }
+}
+
+pub struct SubscribeVolume;
+impl Message for SubscribeVolume {
+    type Result = watch::Receiver<f64>;
+}
+
+#[async_trait]
+impl Handler<SubscribeVolume> for MusicBot {
+    async fn handle(&mut self, _: SubscribeVolume, _: &mut Context<Self>) -> watch::Receiver<f64> {
+        self.volume_updates.subscribe()
+    }
+}
+
+pub struct AddAudio {
+    pub location: AudioLocation,
+    pub user: String,
+}
+
+impl Message for AddAudio {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<AddAudio> for MusicBot {
+    async fn handle(&mut self, r: AddAudio, _: &mut Context<Self>) -> anyhow::Result<()> {
+        // Nobody is an admin over the web interface
+        if self.queue_locked {
+            return Err(QueueLocked.into());
+        }
+
+        self.add_audio(r.location, r.user, None).await
+    }
+}
+
+/// Returned when the web interface tries to change a locked playlist.
+#[derive(Debug)]
+pub struct QueueLocked;
+
+impl std::fmt::Display for QueueLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The playlist is locked, only admins can change it")
+    }
+}
+
+impl std::error::Error for QueueLocked {}
+
+pub struct SetVolume(pub f64);
+impl Message for SetVolume {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<SetVolume> for MusicBot {
+    async fn handle(&mut self, r: SetVolume, _: &mut Context<Self>) -> anyhow::Result<()> {
+        self.set_volume(VolumeChange::Absolute(r.0)).await
+    }
+}
+
+pub struct TakePlaylist;
+impl Message for TakePlaylist {
+    type Result = Vec<AudioMetadata>;
+}
+
+#[async_trait]
+impl Handler<TakePlaylist> for MusicBot {
+    async fn handle(&mut self, _: TakePlaylist, _: &mut Context<Self>) -> Vec<AudioMetadata> {
+        self.playlist.drain_all()
+    }
+}
+
+pub struct SetPlaylist(pub Vec<AudioMetadata>);
+impl Message for SetPlaylist {
+    type Result = ();
+}
+
+#[async_trait]
+impl Handler<SetPlaylist> for MusicBot {
+    async fn handle(&mut self, mut p: SetPlaylist, _: &mut Context<Self>) {
+        self.remove_blocked(&mut p.0);
+        let dropped = self.playlist.replace(p.0).len();
+        if dropped > 0 {
+            warn!(self.logger, "Playlist did not fit"; "dropped" => dropped);
+        }
+    }
+}
+
+pub struct ReorderPlaylist {
+    pub order: Vec<usize>,
+    pub version: u64,
+}
+
+impl Message for ReorderPlaylist {
+    type Result = Result<(), ReorderError>;
+}
+
+#[async_trait]
+impl Handler<ReorderPlaylist> for MusicBot {
+    async fn handle(
+        &mut self,
+        r: ReorderPlaylist,
+        _: &mut Context<Self>,
+    ) -> Result<(), ReorderError> {
+        self.playlist.reorder(&r.order, r.version)
+    }
+}
+
+pub struct AppendPlaylist {
+    pub from: String,
+    pub playlist: Vec<AudioMetadata>,
+}
+
+impl Message for AppendPlaylist {
+    /// How many tracks were added and the ones that did not fit
+    type Result = (usize, Vec<AudioMetadata>);
+}
+
+#[async_trait]
+impl Handler<AppendPlaylist> for MusicBot {
+    async fn handle(
+        &mut self,
+        mut p: AppendPlaylist,
+        _: &mut Context<Self>,
+    ) -> (usize, Vec<AudioMetadata>) {
+        let blocked = self.remove_blocked(&mut p.playlist);
+        let count = p.playlist.len();
+        let left_out = self.playlist.extend(p.playlist);
+        let added = count - left_out.len();
+
+        // The tracks are queued either way, so the ones that did not fit are
+        // still handed back if announcing or starting them fails
+        if let Err(e) = self
+            .on_playlist_received(&p.from, added, blocked, left_out.len())
+            .await
+        {
+            error!(self.logger, "Failed to start transferred playlist"; "error" => %e);
+        }
+
+        (added, left_out)
+    }
+}
+
 pub struct GetChannel;
 impl Message for GetChannel {
     type Result = anyhow::Result<Option<ChannelId>>;
@@ -705,6 +2536,37 @@ impl Handler<GetChannel> for MusicBot {
     }
 }
 
+pub struct GetChannelPath;
+impl Message for GetChannelPath {
+    type Result = anyhow::Result<Option<String>>;
+}
+
+#[async_trait]
+impl Handler<GetChannelPath> for MusicBot {
+    async fn handle(
+        &mut self,
+        _: GetChannelPath,
+        _: &mut Context<Self>,
+    ) -> anyhow::Result<Option<String>> {
+        match &mut self.teamspeak {
+            Some(ts) => ts.my_channel_path().await,
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct GetIdentity;
+impl Message for GetIdentity {
+    type Result = Identity;
+}
+
+#[async_trait]
+impl Handler<GetIdentity> for MusicBot {
+    async fn handle(&mut self, _: GetIdentity, _: &mut Context<Self>) -> Identity {
+        self.identity.clone()
+    }
+}
+
 #[async_trait]
 impl Handler<Quit> for MusicBot {
     async fn handle(&mut self, q: Quit, _: &mut Context<Self>) -> anyhow::Result<()> {
@@ -712,6 +2574,24 @@ impl Handler<Quit> for MusicBot {
     }
 }
 
+/// Quits like `Quit` but keeps the queue in `state_dir` for the next start,
+/// only sent when the whole bot shuts down.
+pub struct Shutdown(pub String);
+impl Message for Shutdown {
+    type Result = anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Handler<Shutdown> for MusicBot {
+    async fn handle(&mut self, s: Shutdown, _: &mut Context<Self>) -> anyhow::Result<()> {
+        if let Err(e) = self.save_state() {
+            warn!(self.logger, "Failed to save state"; "error" => %e);
+        }
+
+        self.quit(s.0, false).await
+    }
+}
+
 #[async_trait]
 impl Handler<MusicBotMessage> for MusicBot {
     async fn handle(&mut self, msg: MusicBotMessage, _: &mut Context<Self>) -> anyhow::Result<()> {
@@ -721,7 +2601,196 @@ impl Handler<MusicBotMessage> for MusicBot {
     }
 }
 
-fn metadata_from_file(path: &Path, user: &str) -> Result<AudioMetadata, anyhow::Error> {
+/// Path of the queue saved as `name` in the playlist directory.
+pub fn saved_queue_path(playlist_dir: Option<&Path>, name: &str) -> anyhow::Result<PathBuf> {
+    let dir = match playlist_dir {
+        Some(dir) => dir,
+        None => anyhow::bail!("playlist_dir was not configured"),
+    };
+
+    // Names must not be able to point outside of the playlist directory
+    if name.is_empty() || name.starts_with('.') || name.contains(&['/', '\\'][..]) {
+        anyhow::bail!("Invalid name");
+    }
+
+    Ok(dir.join(format!("{}.json", name)))
+}
+
+/// Writes tracks in the format `!restore` reads them in.
+pub fn write_tracks(path: &Path, tracks: &[AudioMetadata]) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_vec(tracks)?)?;
+
+    Ok(())
+}
+
+/// Turns the text of `!add` into the locations to load, either a local file,
+/// the urls in the text or a YouTube search for the whole text.
+fn audio_locations(text: String) -> Vec<AudioLocation> {
+    if text.starts_with(FILE_PREFIX) {
+        return vec![AudioLocation::Path(PathBuf::from(
+            &text[FILE_PREFIX.len()..],
+        ))];
+    }
+
+    let urls = extract_urls(&text);
+    if !urls.is_empty() {
+        urls.into_iter()
+            .map(|url| AudioLocation::Url(url.to_owned()))
+            .collect()
+    } else if text.trim().is_empty() || looks_like_url(&text) {
+        vec![AudioLocation::Url(text)]
+    } else {
+        vec![AudioLocation::YoutubeSearch(text)]
+    }
+}
+
+fn looks_like_url(text: &str) -> bool {
+    let text = text.trim();
+
+    text.contains("://") || (text.contains('.') && !text.contains(char::is_whitespace))
+}
+
+/// Sends the encoded audio of the player over the connection.
+///
+/// Has to be called from within the tokio runtime.
+fn audio_callback(
+    mut connection: TeamSpeakConnection,
+    codec: CodecType,
+    logger: Logger,
+) -> Box<dyn FnMut(&[u8]) + Send> {
+    let handle = tokio::runtime::Handle::current();
+
+    Box::new(move |samples| {
+        // Packets are sent very often, so failures are not worth a warning each
+        if let Err(e) = handle.block_on(connection.send_audio_packet(samples, codec)) {
+            debug!(logger, "Failed to send audio packet"; "error" => %e);
+        }
+    })
+}
+
+/// Combines the bot name with a suffix without exceeding the nickname length
+/// limit of TeamSpeak, the name is shortened first.
+fn nickname(name: &str, suffix: &str) -> String {
+    const PREFIX: &str = "🎵 ";
+
+    let fixed = PREFIX.chars().count() + suffix.chars().count();
+    let name_length = MAX_NICKNAME_LENGTH.saturating_sub(fixed).max(1);
+    let name: String = name.chars().take(name_length).collect();
+
+    format!("{}{}{}", PREFIX, name, suffix)
+        .chars()
+        .take(MAX_NICKNAME_LENGTH)
+        .collect()
+}
+
+/// Formats a duration like 01:23, unknown durations are shown as --:--.
+fn clock_time(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => {
+            let secs = duration.as_secs();
+            format!("{:02}:{:02}", secs / 60, secs % 60)
+        }
+        None => String::from("--:--"),
+    }
+}
+
+fn compare_tracks(order: QueueOrder, a: &AudioMetadata, b: &AudioMetadata) -> Ordering {
+    match order {
+        // Tracks of unknown length go last
+        QueueOrder::Duration => {
+            (a.duration.is_none(), a.duration).cmp(&(b.duration.is_none(), b.duration))
+        }
+        QueueOrder::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+        QueueOrder::User => a.added_by.cmp(&b.added_by),
+    }
+}
+
+/// Lists the queue, every entry keeps the number of its position in the play
+/// order even if the list is sorted differently.
+fn queue_text(tracks: Vec<AudioMetadata>, order: Option<QueueOrder>) -> String {
+    if tracks.is_empty() {
+        return String::from("The playlist is empty");
+    }
+
+    let total = tracks.len();
+    let mut entries = tracks.into_iter().enumerate().collect::<Vec<_>>();
+    // Stable sorts keep the play order among equal entries
+    if let Some(order) = order {
+        entries.sort_by(|(_, a), (_, b)| compare_tracks(order, a, b));
+    }
+
+    let mut text = format!("{} tracks in the playlist:", total);
+    for (index, track) in entries.iter().take(MAX_QUEUE_LINES) {
+        let duration = match track.duration {
+            Some(duration) => format!(" ({})", humantime::format_duration(duration)),
+            None => String::new(),
+        };
+        text.push_str(&format!(
+            "\n{}. {}{}, added by {}",
+            index + 1,
+            ts::underline(&track.title),
+            duration,
+            track.added_by
+        ));
+    }
+    if total > MAX_QUEUE_LINES {
+        text.push_str(&format!("\n\u{2026} and {} more", total - MAX_QUEUE_LINES));
+    }
+
+    text
+}
+
+/// Turns the path of a link into search terms,
+/// e.g. `https://example.com/artist/song_title` into `artist song title`.
+fn search_query_from_url(url: &str) -> Option<String> {
+    let without_scheme = url.splitn(2, "://").last()?;
+    let path = without_scheme.splitn(2, '/').nth(1)?;
+    let path = path.split(|c| c == '?' || c == '#').next()?;
+
+    let words: Vec<_> = path
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+/// Finds all http(s) links in a message, ignoring punctuation around them.
+fn extract_urls(text: &str) -> Vec<&str> {
+    let is_wrapping = |c: char| "()<>[]{}\"'.,;:!?".contains(c);
+
+    text.split_whitespace()
+        .map(|word| word.trim_matches(is_wrapping))
+        .filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .collect()
+}
+
+/// Only embedded covers of local files can be used as an avatar,
+/// remote thumbnails would have to be downloaded first.
+fn decode_data_uri(uri: &str) -> Option<Vec<u8>> {
+    let (_, data) = uri.strip_prefix("data:")?.split_once(";base64,")?;
+
+    base64::decode(data).ok()
+}
+
+/// Returns the url of cached art or embeds it as a data URI if the cache is disabled.
+fn cover_url(art_cache: &ArtCache, mime_type: &str, data: &[u8]) -> String {
+    art_cache.insert(mime_type, data).unwrap_or_else(|| {
+        // The image type might be wrong but it does not seem like the big browsers
+        // care so finding the correct type does not seem like it is worth the effort.
+        format!("data:image/jpg;base64,{}", base64::encode(data))
+    })
+}
+
+fn metadata_from_file(
+    path: &Path,
+    user: &str,
+    art_cache: &ArtCache,
+) -> Result<AudioMetadata, anyhow::Error> {
     match path.extension().and_then(|s| s.to_str()) {
         Some("mp3") => {
             let tag = id3::Tag::read_from_path(&path)?;
@@ -734,22 +2803,25 @@ fn metadata_from_file(path: &Path, user: &str) -> Result<AudioMetadata, anyhow::
             let mut cover = None;
             for picture in tag.pictures() {
                 if picture.picture_type == id3::frame::PictureType::CoverFront {
-                    // The image type might be wrong but it does not seem like the big browsers
-                    // care so finding the correct type does not seem like it is worth the effort.
-                    cover = Some(format!(
-                        "data:image/jpg;base64,{}",
-                        base64::encode(&picture.data)
-                    ));
+                    cover = Some(cover_url(art_cache, &picture.mime_type, &picture.data));
                 }
             }
 
+            // The length tag is rarely written, counting the frames always works
+            let duration = match tag.duration() {
+                Some(millis) => Some(Duration::from_millis(millis as u64)),
+                None => mp3_duration::from_path(&path).ok(),
+            };
+
             return Ok(AudioMetadata {
                 uri: format!("{}{}", FILE_PREFIX, path.to_string_lossy()),
                 webpage_url: None,
                 title,
                 thumbnail: cover,
-                duration: tag.duration().map(|s| Duration::from_millis(s as u64)),
+                duration,
                 added_by: user.to_owned(),
+                start_offset: None,
+                end_offset: None,
             });
         }
         Some("flac") => {
@@ -768,20 +2840,29 @@ fn metadata_from_file(path: &Path, user: &str) -> Result<AudioMetadata, anyhow::
             let mut cover = None;
             for picture in tag.pictures() {
                 if picture.picture_type == metaflac::block::PictureType::CoverFront {
-                    cover = Some(format!(
-                        "data:image/jpg;base64,{}",
-                        base64::encode(&picture.data)
-                    ));
+                    cover = Some(cover_url(art_cache, &picture.mime_type, &picture.data));
                 }
             }
 
+            let duration = tag.get_streaminfo().and_then(|info| {
+                if info.sample_rate == 0 || info.total_samples == 0 {
+                    return None;
+                }
+
+                Some(Duration::from_secs_f64(
+                    info.total_samples as f64 / info.sample_rate as f64,
+                ))
+            });
+
             return Ok(AudioMetadata {
                 uri: format!("{}{}", FILE_PREFIX, path.to_string_lossy()),
                 webpage_url: None,
                 title,
                 thumbnail: cover,
-                duration: None,
+                duration,
                 added_by: user.to_owned(),
+                start_offset: None,
+                end_offset: None,
             });
         }
         _ => (),
@@ -815,3 +2896,143 @@ fn spawn_stdin_reader(addr: Address<MusicBot>) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::{
+        audio_locations, compare_tracks, extract_urls, looks_like_url, queue_text,
+        search_query_from_url, AudioLocation, MAX_QUEUE_LINES,
+    };
+    use crate::command::QueueOrder;
+    use crate::youtube_dl::AudioMetadata;
+
+    fn track(title: &str, duration: Option<u64>, added_by: &str) -> AudioMetadata {
+        AudioMetadata {
+            uri: format!("https://example.com/{}", title),
+            webpage_url: None,
+            title: title.to_owned(),
+            thumbnail: None,
+            duration: duration.map(Duration::from_secs),
+            added_by: added_by.to_owned(),
+            start_offset: None,
+            end_offset: None,
+        }
+    }
+
+    #[test]
+    fn compare_tracks_by_order() {
+        let short = track("b", Some(60), "bob");
+        let long = track("A", Some(120), "carol");
+        let unknown = track("c", None, "alice");
+
+        assert_eq!(
+            compare_tracks(QueueOrder::Duration, &short, &long),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_tracks(QueueOrder::Title, &long, &short),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_tracks(QueueOrder::User, &unknown, &short),
+            Ordering::Less
+        );
+
+        // Tracks of unknown length go last and keep their order
+        let mut tracks = [track("x", None, ""), long, track("y", None, ""), short];
+        tracks.sort_by(|a, b| compare_tracks(QueueOrder::Duration, a, b));
+        let titles: Vec<_> = tracks.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(titles, ["b", "A", "x", "y"]);
+    }
+
+    #[test]
+    fn queue_text_keeps_play_order_numbers() {
+        assert_eq!(queue_text(Vec::new(), None), "The playlist is empty");
+
+        let tracks = vec![track("b", Some(90), "bob"), track("a", None, "alice")];
+        assert_eq!(
+            queue_text(tracks.clone(), None),
+            "2 tracks in the playlist:\n\
+             1. [U]b[/U] (1m 30s), added by bob\n\
+             2. [U]a[/U], added by alice"
+        );
+        assert_eq!(
+            queue_text(tracks, Some(QueueOrder::Title)),
+            "2 tracks in the playlist:\n\
+             2. [U]a[/U], added by alice\n\
+             1. [U]b[/U] (1m 30s), added by bob"
+        );
+    }
+
+    #[test]
+    fn queue_text_shortens_long_playlists() {
+        let tracks = (0..MAX_QUEUE_LINES + 3)
+            .map(|i| track(&i.to_string(), None, "bob"))
+            .collect();
+        let text = queue_text(tracks, None);
+
+        assert_eq!(text.lines().count(), MAX_QUEUE_LINES + 2);
+        assert!(text.ends_with("\n\u{2026} and 3 more"));
+    }
+
+    #[test]
+    fn extract_urls_ignores_surrounding_punctuation() {
+        assert_eq!(
+            extract_urls("listen to (https://example.com/a), <http://example.org/b>!"),
+            ["https://example.com/a", "http://example.org/b"]
+        );
+        assert_eq!(
+            extract_urls("\"https://example.com/path?q=1\"."),
+            ["https://example.com/path?q=1"]
+        );
+        assert!(extract_urls("ftp://example.com example.com some words").is_empty());
+    }
+
+    #[test]
+    fn looks_like_url_without_scheme() {
+        assert!(looks_like_url("https://example.com"));
+        assert!(looks_like_url("rtmp://stream"));
+        assert!(looks_like_url(" youtu.be/abc "));
+        assert!(!looks_like_url("never gonna give you up"));
+        assert!(!looks_like_url("mr. brightside"));
+    }
+
+    #[test]
+    fn audio_locations_of_add_text() {
+        assert!(matches!(
+            audio_locations(String::from("file:///music/song.mp3")).as_slice(),
+            [AudioLocation::Path(path)] if path == Path::new("/music/song.mp3")
+        ));
+        assert!(matches!(
+            audio_locations(String::from("https://a.com/1 and https://b.com/2")).as_slice(),
+            [AudioLocation::Url(a), AudioLocation::Url(b)]
+                if a == "https://a.com/1" && b == "https://b.com/2"
+        ));
+        assert!(matches!(
+            audio_locations(String::from("youtu.be/abc")).as_slice(),
+            [AudioLocation::Url(url)] if url == "youtu.be/abc"
+        ));
+        assert!(matches!(
+            audio_locations(String::from("never gonna give you up")).as_slice(),
+            [AudioLocation::YoutubeSearch(query)] if query == "never gonna give you up"
+        ));
+    }
+
+    #[test]
+    fn search_query_from_url_path() {
+        assert_eq!(
+            search_query_from_url("https://example.com/artist/song_title-2?id=1#t"),
+            Some(String::from("artist song title 2"))
+        );
+        assert_eq!(
+            search_query_from_url("https://www.youtube.com/watch?v=dQw4w9WgXcQ"),
+            Some(String::from("watch"))
+        );
+        assert_eq!(search_query_from_url("https://example.com/?q=song"), None);
+        assert_eq!(search_query_from_url("https://example.com"), None);
+    }
+}
@@ -17,11 +17,26 @@ use structopt::StructOpt;
 pub enum Command {
     /// Adds url to playlist
     Add {
+        /// Plays the track next, only for admins
+        #[structopt(long)]
+        top: bool,
         #[structopt(required = true)]
         url: Vec<String>,
     },
-    /// Adds the first video found on YouTube
+    /// Adds url to the front of the playlist so it plays next
+    #[structopt(name = "playnext")]
+    PlayNext {
+        #[structopt(required = true)]
+        url: Vec<String>,
+    },
+    /// Adds the best matching local file or the first video found on YouTube
     Search {
+        /// Only search local files
+        #[structopt(long, conflicts_with = "yt")]
+        local: bool,
+        /// Only search YouTube
+        #[structopt(long)]
+        yt: bool,
         #[structopt(required = true)]
         query: Vec<String>,
     },
@@ -33,26 +48,483 @@ pub enum Command {
     Seek { amount: Seek },
     /// Stops audio playback
     Stop,
-    /// Switches to the next playlist entry
+    /// Switches to the next playlist entry or skips several with a count
     #[structopt(alias = "skip")]
-    Next,
+    Next { count: Option<usize> },
+    /// Jumps to the specified track of the playlist
+    #[structopt(alias = "jump")]
+    Track { number: usize },
     /// Clears the playback queue
     Clear,
-    /// Changes the volume to the specified value
+    /// Removes duplicate entries from the playback queue
+    Dedup,
+    /// Restores the playlist from before the last "!clear"
+    Undo,
+    /// Changes the volume to the specified value or resets it with "reset"
     Volume { volume: VolumeChange },
+    /// Sets the volume to 0 until "!unmute" is used
+    Mute,
+    /// Restores the volume from before "!mute"
+    Unmute,
     /// Leaves the channel
     Leave,
+    /// Restores a playlist that was saved when a bot left
+    Restore { name: String },
+    /// Changes the name the bot is shown with
+    Rename {
+        #[structopt(required = true)]
+        name: Vec<String>,
+    },
+    /// Moves the playlist to another bot
+    Transfer {
+        #[structopt(required = true)]
+        bot: Vec<String>,
+    },
+    /// Measures how long the bot takes to respond
+    Ping,
+    /// Shows the channel the bot is in and how many users listen
+    Channel,
+    /// Shows the version of youtube-dl to check that it works
+    #[structopt(name = "ytdl-version")]
+    YtdlVersion,
+    /// Lists the queued tracks, "--by duration" or "--by user" only sorts the list
+    Queue {
+        #[structopt(long)]
+        by: Option<QueueOrder>,
+    },
+    /// Sorts the playlist by duration, title or user
+    Sort { by: QueueOrder },
+    /// Puts the playlist into a random order
+    Shuffle,
+    /// Removes the specified track from the playlist
+    Remove { index: usize },
+    /// Moves a track of the playlist to another position
+    Move { from: usize, to: usize },
+    /// Saves the current track to your favorites
+    Favorite,
+    /// Lists your favorites
+    Favorites,
+    /// Adds one of your favorites to the playlist
+    #[structopt(name = "playfav")]
+    PlayFav { number: usize },
+    /// Shows how long it takes until the playlist is empty
+    Eta,
+    /// Shows the current track, "!np me" only sends it to you
+    #[structopt(name = "nowplaying", alias = "np")]
+    NowPlaying { target: Option<String> },
+    /// Shows who added the current track
+    Requester,
+    /// Only allows admins to change the playlist
+    Lock,
+    /// Allows everyone to change the playlist again
+    Unlock,
+    /// Repeats the current track or the whole playlist, "off" stops repeating
+    Repeat { mode: RepeatMode },
 }
 
-#[derive(Copy, Clone, Debug)]
+impl Command {
+    /// Commands that change the playlist, which are refused while it is locked.
+    pub fn modifies_queue(&self) -> bool {
+        matches!(
+            self,
+            Command::Add { .. }
+                | Command::PlayNext { .. }
+                | Command::Search { .. }
+                | Command::Next { .. }
+                | Command::Track { .. }
+                | Command::Clear
+                | Command::Dedup
+                | Command::Undo
+                | Command::Sort { .. }
+                | Command::Shuffle
+                | Command::Remove { .. }
+                | Command::Move { .. }
+                | Command::PlayFav { .. }
+                | Command::Restore { .. }
+        )
+    }
+}
+
+/// Usage and examples of every command, shown by `!help <command>`.
+const COMMAND_HELP: &[(&str, &str)] = &[
+    (
+        "add",
+        "!add [--top] <url>... [start-end]\nAdds one or more urls to the playlist, local files \
+         are added with their path relative to the music folder. With a range only that part \
+         is played. Admins can use --top to play a single url next.\n\
+         Examples: !add https://www.youtube.com/watch?v=dQw4w9WgXcQ, \
+         !add https://www.youtube.com/watch?v=dQw4w9WgXcQ 0:43-1:25",
+    ),
+    (
+        "search",
+        "!search [--local|--yt] <query>...\nAdds the best matching local file or, if there \
+         is none, the first video found on YouTube. --local and --yt only search one of them.\n\
+         Examples: !search never gonna give you up, !search --yt never gonna give you up",
+    ),
+    ("play", "!play\nStarts or resumes audio playback."),
+    ("pause", "!pause\nPauses audio playback."),
+    (
+        "seek",
+        "!seek <amount>\nJumps to a position given as time or as percentage of the \
+         track, a leading + or - seeks relative to the current position. The last track \
+         is started again if it already ended or was stopped.\n\
+         Examples: !seek 1m30s, !seek +10s, !seek -1m, !seek 50%, !seek +10%",
+    ),
+    ("stop", "!stop\nStops audio playback."),
+    (
+        "next",
+        "!next [count]\nSwitches to the next playlist entry, with a count that many tracks \
+         are skipped including the current one. Alias: !skip\n\
+         Examples: !next, !skip 3",
+    ),
+    (
+        "track",
+        "!track <number>\nJumps to the specified track of the playlist. Alias: !jump\n\
+         Example: !track 3",
+    ),
+    (
+        "clear",
+        "!clear\nClears the playback queue, !undo brings it back for a few minutes.",
+    ),
+    (
+        "undo",
+        "!undo\nRestores the playlist from before the last !clear, tracks added since then \
+         are queued behind it.",
+    ),
+    (
+        "dedup",
+        "!dedup\nRemoves duplicate entries from the playback queue.",
+    ),
+    (
+        "volume",
+        "!volume <volume>\nChanges the volume in percent, a leading + or - changes \
+         it relative to the current volume and \"reset\" goes back to the default.\n\
+         Examples: !volume 50, !volume +10, !volume reset",
+    ),
+    (
+        "mute",
+        "!mute\nSets the volume to 0, !unmute restores the previous volume.",
+    ),
+    ("unmute", "!unmute\nRestores the volume from before !mute."),
+    ("leave", "!leave\nLeaves the channel."),
+    (
+        "remove",
+        "!remove <number>\nRemoves a track from the playlist, 1 is the track that plays next.\n\
+         Example: !remove 3",
+    ),
+    (
+        "repeat",
+        "!repeat <off|one|all>\nRestarts the current track once it ends with \"one\" or \
+         adds every finished track to the end of the playlist with \"all\". !stop and \
+         !clear do not bring stopped or cleared tracks back.\n\
+         Examples: !repeat one, !repeat off",
+    ),
+    (
+        "rename",
+        "!rename <name>\nChanges the name the bot is shown with until it leaves. \
+         Only admins can rename bots.\n\
+         Example: !rename Party Bot",
+    ),
+    (
+        "restore",
+        "!restore <name>\nRestores a playlist that was saved when a bot left.\n\
+         Example: !restore MusicBot",
+    ),
+    (
+        "transfer",
+        "!transfer <bot>\nMoves the playlist to another bot.\n\
+         Example: !transfer MusicBot",
+    ),
+    (
+        "playnext",
+        "!playnext <url>\nAdds a url or local file in front of the playlist so that it \
+         plays after the current track.\n\
+         Example: !playnext https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+    ),
+    ("ping", "!ping\nMeasures how long the bot takes to respond."),
+    (
+        "ytdl-version",
+        "!ytdl-version\nShows the version of youtube-dl to check that it can be run and is \
+         up to date. Admins only.",
+    ),
+    (
+        "channel",
+        "!channel\nShows the full path of the channel the bot is in and how many users \
+         are listening.",
+    ),
+    (
+        "queue",
+        "!queue [--by duration|title|user]\nLists the queued tracks in play order or sorted \
+         by their length, title or by who added them. Sorting the list does not change the \
+         play order, use !sort for that.\n\
+         Examples: !queue, !queue --by user",
+    ),
+    (
+        "eta",
+        "!eta\nShows how long the current track and the queue play for. \
+         Tracks of unknown length are left out of the estimate.",
+    ),
+    (
+        "shuffle",
+        "!shuffle\nPuts the playlist into a random order, the current track keeps playing.",
+    ),
+    (
+        "sort",
+        "!sort <duration|title|user>\nSorts the playlist, the current track keeps playing.\n\
+         Example: !sort duration",
+    ),
+    (
+        "favorite",
+        "!favorite\nSaves the current track to your favorites, list them with !favorites \
+         and add one to the playlist with !playfav.",
+    ),
+    (
+        "favorites",
+        "!favorites\nLists your favorites, only you see the list.",
+    ),
+    (
+        "playfav",
+        "!playfav <number>\nAdds one of your favorites to the playlist, the number is the \
+         one shown by !favorites.\n\
+         Example: !playfav 2",
+    ),
+    (
+        "move",
+        "!move <from> <to>\nMoves a track to another position of the playlist, the numbers \
+         are the ones shown by !queue.\n\
+         Example: !move 5 1",
+    ),
+    (
+        "requester",
+        "!requester\nShows who added the current track to the playlist.",
+    ),
+    (
+        "nowplaying",
+        "!nowplaying [me]\nShows the current track, with \"me\" it is only sent to you. \
+         Alias: !np\n\
+         Examples: !np, !np me",
+    ),
+    (
+        "lock",
+        "!lock\nOnly allows admins to change the playlist, e.g. during events with a DJ. \
+         Admins only.",
+    ),
+    (
+        "unlock",
+        "!unlock\nAllows everyone to change the playlist again. Admins only.",
+    ),
+];
+
+/// Returns the detailed help of a command or a suggestion if it is unknown.
+pub fn command_help(name: &str) -> String {
+    let name = name.trim_start_matches('!').to_lowercase();
+    let name = match name.as_str() {
+        "skip" => "next",
+        "jump" => "track",
+        "np" => "nowplaying",
+        name => name,
+    };
+
+    if let Some((_, help)) = COMMAND_HELP.iter().find(|(command, _)| *command == name) {
+        return String::from(*help);
+    }
+
+    let closest = COMMAND_HELP
+        .iter()
+        .map(|(command, _)| (edit_distance(name, command), command))
+        .min_by_key(|(distance, _)| *distance);
+
+    match closest {
+        Some((distance, command)) if distance <= 2 => format!(
+            "Unknown command \"{}\", did you mean \"{}\"?",
+            name, command
+        ),
+        _ => format!("Unknown command \"{}\", see !help for a list", name),
+    }
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Part of a track given as `start-end`, e.g. `0:30-1:15`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClipRange {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClipRangeError {
+    InvalidFormat,
+    EndBeforeStart,
+}
+
+impl std::fmt::Display for ClipRangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClipRangeError::InvalidFormat => write!(f, "Expected a range like 0:30-1:15"),
+            ClipRangeError::EndBeforeStart => write!(f, "The clip has to end after it starts"),
+        }
+    }
+}
+
+impl std::error::Error for ClipRangeError {}
+
+impl std::str::FromStr for ClipRange {
+    type Err = ClipRangeError;
+
+    fn from_str(range: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = range.splitn(2, '-');
+        let start = parts.next().and_then(parse_timestamp);
+        let end = parts.next().and_then(parse_timestamp);
+
+        match (start, end) {
+            (Some(start), Some(end)) if end > start => Ok(ClipRange { start, end }),
+            (Some(_), Some(_)) => Err(ClipRangeError::EndBeforeStart),
+            _ => Err(ClipRangeError::InvalidFormat),
+        }
+    }
+}
+
+/// Parses timestamps like `1:02:03`, `2:03` or `3`.
+fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let mut parts = timestamp.split(':').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return None;
+    }
+    parts.reverse();
+
+    let mut seconds = 0;
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let value: u64 = part.parse().ok()?;
+        // Minutes and seconds have to be below 60 unless they come first
+        if i + 1 < parts.len() && value >= 60 {
+            return None;
+        }
+
+        seconds = value
+            .checked_mul(60u64.pow(i as u32))
+            .and_then(|s| s.checked_add(seconds))?;
+    }
+
+    Some(Duration::from_secs(seconds))
+}
+
+/// Order `!queue` lists the tracks in or `!sort` sorts them in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QueueOrder {
+    Duration,
+    Title,
+    User,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct QueueOrderError;
+
+impl std::fmt::Display for QueueOrderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Expected \"duration\", \"title\" or \"user\"")
+    }
+}
+
+impl std::error::Error for QueueOrderError {}
+
+impl std::str::FromStr for QueueOrder {
+    type Err = QueueOrderError;
+
+    fn from_str(order: &str) -> std::result::Result<Self, Self::Err> {
+        match order.to_lowercase().as_str() {
+            "duration" | "length" => Ok(QueueOrder::Duration),
+            "title" => Ok(QueueOrder::Title),
+            "user" => Ok(QueueOrder::User),
+            _ => Err(QueueOrderError),
+        }
+    }
+}
+
+/// What is played again once a track ends.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RepeatMode {
+    Off,
+    /// Restarts the track that ended
+    One,
+    /// Queues the track that ended again so the playlist cycles
+    All,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RepeatModeError;
+
+impl std::fmt::Display for RepeatModeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Expected \"off\", \"one\" or \"all\"")
+    }
+}
+
+impl std::error::Error for RepeatModeError {}
+
+impl std::str::FromStr for RepeatMode {
+    type Err = RepeatModeError;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode.to_lowercase().as_str() {
+            "off" => Ok(RepeatMode::Off),
+            "one" | "track" => Ok(RepeatMode::One),
+            "all" | "playlist" => Ok(RepeatMode::All),
+            _ => Err(RepeatModeError),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Seek {
     Positive(Duration),
     Negative(Duration),
     Absolute(Duration),
+    /// Fractions of the track duration, e.g. `50%` is stored as 0.5
+    PositivePercent(f64),
+    NegativePercent(f64),
+    AbsolutePercent(f64),
 }
 
+#[derive(Debug)]
+pub enum SeekParseError {
+    Duration(humantime::DurationError),
+    Percent(std::num::ParseFloatError),
+    NegativePercent,
+}
+
+impl std::fmt::Display for SeekParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SeekParseError::Duration(e) => write!(f, "{}", e),
+            SeekParseError::Percent(e) => write!(f, "Invalid percentage: {}", e),
+            SeekParseError::NegativePercent => write!(f, "Use - to seek backwards"),
+        }
+    }
+}
+
+impl std::error::Error for SeekParseError {}
+
 impl std::str::FromStr for Seek {
-    type Err = humantime::DurationError;
+    type Err = SeekParseError;
 
     fn from_str(mut amount: &str) -> std::result::Result<Self, Self::Err> {
         let sign = match amount.chars().next() {
@@ -66,7 +538,20 @@ impl std::str::FromStr for Seek {
             amount = &amount[1..];
         }
 
-        let duration = humantime::parse_duration(amount)?;
+        if let Some(percent) = amount.strip_suffix('%') {
+            let fraction = f64::from_str(percent).map_err(SeekParseError::Percent)? * 0.01;
+            if fraction.is_nan() || fraction < 0.0 {
+                return Err(SeekParseError::NegativePercent);
+            }
+
+            return match sign {
+                1 => Ok(Seek::PositivePercent(fraction)),
+                -1 => Ok(Seek::NegativePercent(fraction)),
+                _ => Ok(Seek::AbsolutePercent(fraction)),
+            };
+        }
+
+        let duration = humantime::parse_duration(amount).map_err(SeekParseError::Duration)?;
 
         match sign {
             1 => Ok(Seek::Positive(duration)),
@@ -81,6 +566,8 @@ pub enum VolumeChange {
     Positive(f64),
     Negative(f64),
     Absolute(f64),
+    /// Goes back to the configured default volume
+    Reset,
 }
 
 // TODO This runs twice, report to clap?
@@ -88,6 +575,10 @@ impl std::str::FromStr for VolumeChange {
     type Err = std::num::ParseFloatError;
 
     fn from_str(mut amount: &str) -> std::result::Result<Self, Self::Err> {
+        if amount == "reset" || amount == "default" {
+            return Ok(VolumeChange::Reset);
+        }
+
         let sign = match amount.chars().next() {
             Some('+') => 1,
             Some('-') => -1,
@@ -108,3 +599,140 @@ impl std::str::FromStr for VolumeChange {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        ClipRange, ClipRangeError, Command, QueueOrder, RepeatMode, RepeatModeError, Seek,
+    };
+    use structopt::StructOpt;
+
+    #[test]
+    fn seek_by_time() {
+        assert_eq!(
+            "1m30s".parse::<Seek>().unwrap(),
+            Seek::Absolute(Duration::from_secs(90))
+        );
+        assert_eq!(
+            "+10s".parse::<Seek>().unwrap(),
+            Seek::Positive(Duration::from_secs(10))
+        );
+        assert_eq!(
+            "-1m".parse::<Seek>().unwrap(),
+            Seek::Negative(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn seek_by_percent() {
+        assert_eq!("50%".parse::<Seek>().unwrap(), Seek::AbsolutePercent(0.5));
+        assert_eq!("+10%".parse::<Seek>().unwrap(), Seek::PositivePercent(0.1));
+        assert_eq!("-25%".parse::<Seek>().unwrap(), Seek::NegativePercent(0.25));
+    }
+
+    #[test]
+    fn invalid_seek() {
+        assert!("+%".parse::<Seek>().is_err());
+        assert!("ten%".parse::<Seek>().is_err());
+        assert!("+-10%".parse::<Seek>().is_err());
+        assert!("soon".parse::<Seek>().is_err());
+    }
+
+    #[test]
+    fn search_scope() {
+        match Command::from_iter_safe(&["search", "--local", "never", "gonna"]) {
+            Ok(Command::Search { local, yt, query }) => {
+                assert!(local && !yt);
+                assert_eq!(query, ["never", "gonna"]);
+            }
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+        assert!(Command::from_iter_safe(&["search", "--local", "--yt", "q"]).is_err());
+    }
+
+    #[test]
+    fn queue_order() {
+        assert!(matches!(
+            Command::from_iter_safe(&["queue", "--by", "Duration"]),
+            Ok(Command::Queue {
+                by: Some(QueueOrder::Duration)
+            })
+        ));
+        assert!(matches!(
+            Command::from_iter_safe(&["sort", "title"]),
+            Ok(Command::Sort {
+                by: QueueOrder::Title
+            })
+        ));
+        assert!(Command::from_iter_safe(&["queue", "--by", "name"]).is_err());
+    }
+
+    #[test]
+    fn repeat_mode() {
+        assert!(matches!(
+            Command::from_iter_safe(&["repeat", "All"]),
+            Ok(Command::Repeat {
+                mode: RepeatMode::All
+            })
+        ));
+        assert_eq!("track".parse(), Ok(RepeatMode::One));
+        assert_eq!("loop".parse::<RepeatMode>(), Err(RepeatModeError));
+    }
+
+    #[test]
+    fn next_count() {
+        assert!(matches!(
+            Command::from_iter_safe(&["skip", "3"]),
+            Ok(Command::Next { count: Some(3) })
+        ));
+        assert!(matches!(
+            Command::from_iter_safe(&["next"]),
+            Ok(Command::Next { count: None })
+        ));
+    }
+
+    #[test]
+    fn clip_range() {
+        assert_eq!(
+            "0:30-1:15".parse::<ClipRange>(),
+            Ok(ClipRange {
+                start: Duration::from_secs(30),
+                end: Duration::from_secs(75),
+            })
+        );
+        assert_eq!(
+            "1:00:00-1:00:10".parse::<ClipRange>().map(|r| r.end),
+            Ok(Duration::from_secs(3610))
+        );
+        assert_eq!(
+            "10-20".parse::<ClipRange>().map(|r| r.start),
+            Ok(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn invalid_clip_range() {
+        assert_eq!(
+            "1:15-0:30".parse::<ClipRange>(),
+            Err(ClipRangeError::EndBeforeStart)
+        );
+        assert_eq!(
+            "0:30-0:30".parse::<ClipRange>(),
+            Err(ClipRangeError::EndBeforeStart)
+        );
+        assert_eq!(
+            "0:75-1:00".parse::<ClipRange>(),
+            Err(ClipRangeError::InvalidFormat)
+        );
+        assert_eq!(
+            "https://example.com".parse::<ClipRange>(),
+            Err(ClipRangeError::InvalidFormat)
+        );
+        assert_eq!(
+            "0:30".parse::<ClipRange>(),
+            Err(ClipRangeError::InvalidFormat)
+        );
+    }
+}
@@ -1,7 +1,31 @@
 // TODO Temporary file until we have a better logging setup for slog
 
-use slog::{Drain, KV};
+use slog::{Drain, Level, KV};
 use std::fmt::{self, Arguments, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Levels in the order of their discriminants
+const LEVELS: [Level; 6] = [
+    Level::Critical,
+    Level::Error,
+    Level::Warning,
+    Level::Info,
+    Level::Debug,
+    Level::Trace,
+];
+
+/// Most verbose level that is passed on, can be changed while running
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Debug as usize);
+
+pub fn max_level() -> Level {
+    LEVELS[MAX_LEVEL.load(Ordering::Relaxed)]
+}
+
+/// Changes which messages are logged, levels that are disabled in
+/// log4rs.yml are still not logged.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as usize, Ordering::Relaxed);
+}
 
 pub struct LogBridge<T>(pub T);
 
@@ -10,6 +34,10 @@ impl<T: log::Log> Drain for LogBridge<T> {
     type Err = slog::Error;
 
     fn log(&self, record: &slog::Record, kvs: &slog::OwnedKVList) -> Result<(), Self::Err> {
+        if !record.level().is_at_least(max_level()) {
+            return Ok(());
+        }
+
         let mut target = record.tag();
         if target.is_empty() {
             target = record.module();
@@ -32,6 +60,10 @@ impl<T: log::Log> Drain for LogBridge<T> {
     }
 
     fn is_enabled(&self, level: slog::Level) -> bool {
+        if !level.is_at_least(max_level()) {
+            return false;
+        }
+
         let meta = log::Metadata::builder().level(level_to_log(level)).build();
 
         self.0.enabled(&meta)
@@ -2,6 +2,7 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::thread;
+use std::time::Duration;
 
 use slog::{debug, error, info, o, Drain, Logger};
 use slog_async::OverflowStrategy;
@@ -11,6 +12,7 @@ use structopt::StructOpt;
 use tokio::signal::unix::*;
 use tsclientlib::Identity;
 
+mod art_cache;
 mod audio_player;
 mod bot;
 mod command;
@@ -20,7 +22,11 @@ mod teamspeak;
 mod web_server;
 mod youtube_dl;
 
-use bot::{MasterArgs, MasterBot, MusicBot, MusicBotArgs, Quit};
+use art_cache::ArtCache;
+use bot::{
+    parse_client_version, parse_codec, Blocklist, ConnectRetry, MasterArgs, MasterBot, MusicBot,
+    MusicBotArgs, Quit, StateLabels,
+};
 use log_bridge::LogBridge;
 
 #[derive(StructOpt, Debug)]
@@ -66,20 +72,22 @@ pub struct Args {
         parse(from_occurrences)
     )]
     verbose: u8,
+    /// Skips checking that youtube-dl and the GStreamer plugins work on startup
+    #[structopt(long)]
+    skip_checks: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    let root_logger = {
+    let (root_logger, log_guard) = {
         let config = log4rs::config::load_config_file("log4rs.yml", Default::default()).unwrap();
         let drain = LogBridge(log4rs::Logger::new(config)).fuse();
         // slog_async adds a channel because log4rs if not unwind safe
-        let drain = slog_async::Async::new(drain)
+        let (drain, guard) = slog_async::Async::new(drain)
             .overflow_strategy(OverflowStrategy::Block)
-            .build()
-            .fuse();
+            .build_with_guard();
 
-        Logger::root(drain, o!())
+        (Logger::root(drain.fuse(), o!()), guard)
     };
 
     let scope_guard = slog_scope::set_global_logger(root_logger.clone());
@@ -91,6 +99,11 @@ async fn main() {
 
     if let Err(e) = run(root_logger.clone()).await {
         error!(root_logger, "{}", e);
+
+        // Supervisors like systemd only notice the failure from the exit code,
+        // the guard flushes the log first
+        drop(log_guard);
+        std::process::exit(1);
     }
 }
 
@@ -170,8 +183,24 @@ async fn run(root_logger: Logger) -> Result<(), anyhow::Error> {
     }
 
     let local = args.local;
+    let skip_checks = args.skip_checks;
     let bot_args = config.merge(args);
 
+    if !skip_checks {
+        // Fail now instead of when the first track is added
+        if let Err(e) = audio_player::check_plugins(local) {
+            anyhow::bail!("{} (use --skip-checks to start anyway)", e);
+        }
+
+        match youtube_dl::version().await {
+            Ok(version) => info!(root_logger, "Found youtube-dl"; "version" => version),
+            Err(e) => anyhow::bail!(
+                "youtube-dl does not work: {} (use --skip-checks to start anyway)",
+                e
+            ),
+        }
+    }
+
     info!(root_logger, "Starting PokeBot!");
     debug!(root_logger, "Received CLI arguments"; "args" => ?std::env::args());
 
@@ -190,22 +219,63 @@ async fn run(root_logger: Logger) -> Result<(), anyhow::Error> {
             verbose: bot_args.verbose,
             logger: root_logger,
             volume: bot_args.volume,
+            retry: ConnectRetry {
+                attempts: bot_args.connect_attempts,
+                backoff: Duration::from_secs(bot_args.connect_backoff),
+            },
+            admins: bot_args.admins,
+            leave_warning: Duration::from_secs(bot_args.leave_warning),
+            verbose_feedback: bot_args.verbose_feedback,
+            avatar_from_thumbnail: bot_args.avatar_from_thumbnail,
+            playlist_dir: bot_args.playlist_dir,
+            private_errors: bot_args.private_errors,
+            state_labels: StateLabels {
+                playing: bot_args.playing_label,
+                paused: bot_args.paused_label,
+                stopped: bot_args.stopped_label,
+            },
+            search_on_url_failure: bot_args.search_on_url_failure,
+            domain_cooldown: Duration::from_secs(bot_args.domain_cooldown),
+            resume_after_error: bot_args.resume_after_error,
+            art_cache: ArtCache::new(bot_args.art_cache_size),
+            connection: None,
+            client_version: parse_client_version(bot_args.client_version.as_deref())?,
+            queue_max_time: bot_args.queue_max_time.map(Duration::from_millis),
+            queue_max_bytes: bot_args.queue_max_bytes,
+            expand_playlists: bot_args.expand_playlists,
+            announce_next: Duration::from_secs(bot_args.announce_next),
+            show_volume_in_name: bot_args.show_volume_in_name,
+            resample_quality: bot_args.resample_quality,
+            codec: parse_codec(bot_args.codec.as_deref())?,
+            stall_timeout: Duration::from_secs(bot_args.stall_timeout),
+            favorites_dir: bot_args.favorites_dir,
+            playlist_max_len: bot_args.playlist_max_len,
+            state_dir: bot_args.state_dir,
+            blocklist: Blocklist::new(&bot_args.blocklist),
         };
-        MusicBot::spawn(bot_args).await;
+        MusicBot::spawn(bot_args).await?;
 
         ctrl_c.await??;
     } else {
         let webserver_enable = bot_args.webserver_enable;
         let domain = bot_args.domain.clone();
         let bind_address = bot_args.bind_address.clone();
+        let cors_origins = bot_args.cors_origins.clone();
+        let upload = web_server::UploadConfig {
+            music_root: bot_args.music_root.clone(),
+            upload_dir: bot_args.upload_dir.clone(),
+            max_size: bot_args.max_upload_size,
+        };
         let bot_name = bot_args.master_name.clone();
         let bot_logger = root_logger.new(o!("master" => bot_name.clone()));
-        let bot = MasterBot::spawn(bot_args, bot_logger).await;
+        let bot = MasterBot::spawn(bot_args, bot_logger).await?;
 
         if webserver_enable {
             let web_args = web_server::WebServerArgs {
                 domain,
                 bind_address,
+                cors_origins,
+                upload,
                 bot: bot.downgrade(),
             };
             spawn_web_server(web_args, root_logger.new(o!("webserver" => bot_name)));
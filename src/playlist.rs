@@ -1,11 +1,19 @@
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
 
+use rand::{seq::SliceRandom, Rng};
 use slog::{info, Logger};
 
 use crate::youtube_dl::AudioMetadata;
 
 pub struct Playlist {
     data: VecDeque<AudioMetadata>,
+    /// Increased on every change, used to detect concurrent edits
+    version: u64,
+    /// Number of entries after which adding is rejected, the track that is
+    /// currently playing is not part of the playlist and does not count
+    max_len: Option<usize>,
     logger: Logger,
 }
 
@@ -13,18 +21,57 @@ impl Playlist {
     pub fn new(logger: Logger) -> Self {
         Self {
             data: VecDeque::new(),
+            version: 0,
+            max_len: None,
             logger,
         }
     }
 
-    pub fn push(&mut self, data: AudioMetadata) {
+    pub fn with_max_len(mut self, max_len: Option<usize>) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Returns how many entries can still be added and the maximum length,
+    /// `None` if there is no limit.
+    pub fn remaining(&self) -> Option<(usize, usize)> {
+        self.max_len
+            .map(|max_len| (max_len.saturating_sub(self.data.len()), max_len))
+    }
+
+    fn check_len(&self) -> Result<(), PlaylistFull> {
+        match self.remaining() {
+            Some((0, max_len)) => Err(PlaylistFull(max_len)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn push(&mut self, data: AudioMetadata) -> Result<(), PlaylistFull> {
+        self.check_len()?;
         info!(self.logger, "Adding to playlist"; "title" => &data.title);
 
-        self.data.push_front(data)
+        self.version += 1;
+        self.data.push_front(data);
+
+        Ok(())
+    }
+
+    /// Adds an entry in front of all others so that it is played next.
+    pub fn push_next(&mut self, data: AudioMetadata) -> Result<(), PlaylistFull> {
+        self.check_len()?;
+        info!(self.logger, "Adding to front of playlist"; "title" => &data.title);
+
+        self.version += 1;
+        self.data.push_back(data);
+
+        Ok(())
     }
 
     pub fn pop(&mut self) -> Option<AudioMetadata> {
         let res = self.data.pop_back();
+        if res.is_some() {
+            self.version += 1;
+        }
         info!(
             self.logger,
             "Popping from playlist";
@@ -34,6 +81,191 @@ impl Playlist {
         res
     }
 
+    /// Adds the items behind all others, the ones that do not fit into a
+    /// limited playlist are left out and returned.
+    pub fn extend(&mut self, mut items: Vec<AudioMetadata>) -> Vec<AudioMetadata> {
+        let left_out = match self.remaining() {
+            Some((remaining, _)) if remaining < items.len() => items.split_off(remaining),
+            _ => Vec::new(),
+        };
+        info!(
+            self.logger,
+            "Adding to playlist";
+            "count" => items.len(),
+            "dropped" => left_out.len()
+        );
+
+        self.version += 1;
+        for item in items {
+            self.data.push_front(item);
+        }
+
+        left_out
+    }
+
+    /// Replaces the whole queue, the first item will be played next.
+    /// Returns the items that did not fit like `extend`.
+    pub fn replace(&mut self, items: Vec<AudioMetadata>) -> Vec<AudioMetadata> {
+        self.data.clear();
+        self.extend(items)
+    }
+
+    /// Reorders the queue, `order` contains the current index of every
+    /// entry in the new order.
+    pub fn reorder(&mut self, order: &[usize], version: u64) -> Result<(), ReorderError> {
+        if version != self.version {
+            return Err(ReorderError::VersionMismatch);
+        }
+
+        let current = self.to_vec();
+        if order.len() != current.len() {
+            return Err(ReorderError::LengthMismatch);
+        }
+
+        let mut seen = vec![false; current.len()];
+        for &i in order {
+            if i >= seen.len() || seen[i] {
+                return Err(ReorderError::InvalidOrder);
+            }
+            seen[i] = true;
+        }
+
+        self.data = order.iter().rev().map(|&i| current[i].clone()).collect();
+        self.version += 1;
+
+        info!(self.logger, "Reordered playlist");
+
+        Ok(())
+    }
+
+    pub fn drain_all(&mut self) -> Vec<AudioMetadata> {
+        let res = self.to_vec();
+        self.data.clear();
+        self.version += 1;
+
+        info!(self.logger, "Drained playlist"; "count" => res.len());
+
+        res
+    }
+
+    /// Removes entries that are already queued before them,
+    /// returns the number of removed entries.
+    pub fn dedup(&mut self) -> usize {
+        let mut items = self.to_vec();
+        let before = items.len();
+
+        let mut seen = HashSet::new();
+        // The stream uri changes on every lookup, so the page is compared if it exists
+        items.retain(|item| {
+            seen.insert(item.webpage_url.clone().unwrap_or_else(|| item.uri.clone()))
+        });
+
+        let removed = before - items.len();
+        if removed > 0 {
+            self.data = items.into_iter().rev().collect();
+            self.version += 1;
+        }
+
+        info!(self.logger, "Removed duplicates from playlist"; "count" => removed);
+
+        removed
+    }
+
+    /// Sorts the queue, the first entry after sorting is played next.
+    /// Entries that compare equal keep their order.
+    pub fn sort_by<F>(&mut self, compare: F)
+    where
+        F: FnMut(&AudioMetadata, &AudioMetadata) -> Ordering,
+    {
+        let mut items = self.to_vec();
+        items.sort_by(compare);
+
+        self.data = items.into_iter().rev().collect();
+        self.version += 1;
+
+        info!(self.logger, "Sorted playlist"; "count" => self.data.len());
+    }
+
+    /// Removes the entry at a 1-based position in play order, so 1 is the
+    /// entry that is played next.
+    pub fn remove(&mut self, index: usize) -> Option<AudioMetadata> {
+        if index == 0 || index > self.data.len() {
+            return None;
+        }
+
+        let res = self.data.remove(self.data.len() - index);
+        self.version += 1;
+        info!(
+            self.logger,
+            "Removed from playlist";
+            "title" => res.as_ref().map(|r| &r.title)
+        );
+
+        res
+    }
+
+    /// Moves an entry to another position, both are 1-based positions in play
+    /// order. Returns the moved entry or `None` if a position is out of range.
+    pub fn move_entry(&mut self, from: usize, to: usize) -> Option<&AudioMetadata> {
+        let len = self.data.len();
+        if from == 0 || from > len || to == 0 || to > len {
+            return None;
+        }
+
+        let mut items = self.to_vec();
+        let item = items.remove(from - 1);
+        items.insert(to - 1, item);
+
+        self.data = items.into_iter().rev().collect();
+        self.version += 1;
+
+        info!(self.logger, "Moved entry in playlist"; "from" => from, "to" => to);
+
+        self.data.get(len - to)
+    }
+
+    /// Puts the queued entries into a random order.
+    pub fn shuffle<R: Rng + ?Sized>(&mut self, rng: &mut R) {
+        let mut items = self.to_vec();
+        items.shuffle(rng);
+
+        self.data = items.into_iter().rev().collect();
+        self.version += 1;
+
+        info!(self.logger, "Shuffled playlist"; "count" => self.data.len());
+    }
+
+    pub fn peek(&self) -> Option<&AudioMetadata> {
+        self.data.back()
+    }
+
+    /// Drops the first `count` entries so the one after them is played next.
+    pub fn skip(&mut self, count: usize) {
+        let count = count.min(self.data.len());
+        self.data.truncate(self.data.len() - count);
+        self.version += 1;
+
+        info!(self.logger, "Skipped entries in playlist"; "count" => count);
+    }
+
+    /// Sums up how long the queued tracks play for, tracks of unknown length
+    /// are left out. `None` is only returned if the queue is empty.
+    pub fn total_duration(&self) -> Option<Duration> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        Some(self.data.iter().filter_map(play_time).sum())
+    }
+
+    /// Number of queued tracks that `total_duration` leaves out.
+    pub fn unknown_durations(&self) -> usize {
+        self.data
+            .iter()
+            .filter(|track| play_time(track).is_none())
+            .count()
+    }
+
     pub fn to_vec(&self) -> Vec<AudioMetadata> {
         let (a, b) = self.data.as_slices();
 
@@ -44,13 +276,330 @@ impl Playlist {
         res
     }
 
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.data.is_empty()
     }
 
     pub fn clear(&mut self) {
         self.data.clear();
+        self.version += 1;
 
         info!(self.logger, "Cleared playlist")
     }
 }
+
+/// How long a track plays for, clips only play between their offsets.
+fn play_time(track: &AudioMetadata) -> Option<Duration> {
+    let end = track.end_offset.or(track.duration)?;
+
+    Some(end.saturating_sub(track.start_offset.unwrap_or_default()))
+}
+
+#[derive(Debug)]
+pub enum ReorderError {
+    VersionMismatch,
+    LengthMismatch,
+    InvalidOrder,
+}
+
+impl std::fmt::Display for ReorderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ReorderError::*;
+        match self {
+            VersionMismatch | LengthMismatch => {
+                write!(f, "The playlist was changed in the meantime")
+            }
+            InvalidOrder => write!(f, "The new order has to contain every entry exactly once"),
+        }
+    }
+}
+
+/// Returned when adding to a playlist that reached its maximum length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlaylistFull(pub usize);
+
+impl std::fmt::Display for PlaylistFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Playlist is full (max {})", self.0)
+    }
+}
+
+impl std::error::Error for PlaylistFull {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
+    use slog::{o, Discard, Logger};
+
+    use super::{Playlist, PlaylistFull, ReorderError};
+    use crate::youtube_dl::AudioMetadata;
+
+    fn track(title: &str) -> AudioMetadata {
+        AudioMetadata {
+            uri: format!("https://example.com/{}", title),
+            webpage_url: None,
+            title: title.to_owned(),
+            thumbnail: None,
+            duration: None,
+            added_by: String::new(),
+            start_offset: None,
+            end_offset: None,
+        }
+    }
+
+    #[test]
+    fn remove() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        for title in &["a", "b", "c"] {
+            playlist.push(track(title)).unwrap();
+        }
+
+        assert!(playlist.remove(0).is_none());
+        assert!(playlist.remove(4).is_none());
+        assert_eq!(playlist.remove(1).unwrap().title, "a");
+        assert_eq!(playlist.remove(2).unwrap().title, "c");
+        assert_eq!(playlist.pop().unwrap().title, "b");
+    }
+
+    #[test]
+    fn move_entry() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        for title in &["a", "b", "c"] {
+            playlist.push(track(title)).unwrap();
+        }
+        let titles = |playlist: &Playlist| {
+            playlist
+                .to_vec()
+                .into_iter()
+                .map(|t| t.title)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(playlist.move_entry(0, 1).is_none());
+        assert!(playlist.move_entry(1, 4).is_none());
+        assert_eq!(titles(&playlist), ["a", "b", "c"]);
+
+        assert_eq!(playlist.move_entry(1, 3).unwrap().title, "a");
+        assert_eq!(titles(&playlist), ["b", "c", "a"]);
+
+        assert_eq!(playlist.move_entry(3, 1).unwrap().title, "a");
+        assert_eq!(titles(&playlist), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn max_len() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!())).with_max_len(Some(2));
+        assert_eq!(playlist.remaining(), Some((2, 2)));
+        playlist.push(track("a")).unwrap();
+        playlist.push_next(track("b")).unwrap();
+        assert_eq!(playlist.remaining(), Some((0, 2)));
+
+        assert_eq!(playlist.push(track("c")), Err(PlaylistFull(2)));
+        assert_eq!(playlist.push_next(track("c")), Err(PlaylistFull(2)));
+        assert_eq!(PlaylistFull(2).to_string(), "Playlist is full (max 2)");
+
+        assert_eq!(playlist.pop().unwrap().title, "b");
+        playlist.push(track("c")).unwrap();
+        assert_eq!(playlist.len(), 2);
+
+        assert!(playlist.replace(vec![track("d")]).is_empty());
+        let left_out = playlist.extend(vec![track("e"), track("f")]);
+        assert_eq!(left_out.len(), 1);
+        assert_eq!(left_out[0].title, "f");
+        assert_eq!(playlist.pop().unwrap().title, "d");
+        assert_eq!(playlist.pop().unwrap().title, "e");
+        assert!(playlist.pop().is_none());
+    }
+
+    #[test]
+    fn shuffle() {
+        let titles: Vec<_> = (0..10).map(|i| i.to_string()).collect();
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        for title in &titles {
+            playlist.push(track(title)).unwrap();
+        }
+
+        let mut expected = titles.clone();
+        expected.shuffle(&mut SmallRng::seed_from_u64(7));
+        playlist.shuffle(&mut SmallRng::seed_from_u64(7));
+
+        let order: Vec<_> = playlist.to_vec().into_iter().map(|t| t.title).collect();
+        assert_eq!(order, expected);
+        assert_ne!(order, titles);
+        assert_eq!(playlist.pop().unwrap().title, expected[0]);
+    }
+
+    #[test]
+    fn total_duration() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        assert_eq!(playlist.total_duration(), None);
+
+        playlist.push(track("unknown")).unwrap();
+        assert_eq!(playlist.total_duration(), Some(Duration::from_secs(0)));
+        assert_eq!(playlist.unknown_durations(), 1);
+
+        let mut full = track("full");
+        full.duration = Some(Duration::from_secs(120));
+        let mut clip = track("clip");
+        clip.duration = Some(Duration::from_secs(300));
+        clip.start_offset = Some(Duration::from_secs(60));
+        clip.end_offset = Some(Duration::from_secs(90));
+        let mut started = track("started");
+        started.duration = Some(Duration::from_secs(100));
+        started.start_offset = Some(Duration::from_secs(40));
+        playlist.extend(vec![full, clip, started]);
+
+        assert_eq!(playlist.total_duration(), Some(Duration::from_secs(210)));
+        assert_eq!(playlist.unknown_durations(), 1);
+    }
+
+    #[test]
+    fn reorder() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        for title in &["a", "b", "c"] {
+            playlist.push(track(title)).unwrap();
+        }
+        let version = playlist.version();
+
+        assert!(matches!(
+            playlist.reorder(&[2, 0, 1], version + 1),
+            Err(ReorderError::VersionMismatch)
+        ));
+        assert!(matches!(
+            playlist.reorder(&[1, 0], version),
+            Err(ReorderError::LengthMismatch)
+        ));
+        assert!(matches!(
+            playlist.reorder(&[0, 0, 1], version),
+            Err(ReorderError::InvalidOrder)
+        ));
+        assert!(matches!(
+            playlist.reorder(&[0, 1, 3], version),
+            Err(ReorderError::InvalidOrder)
+        ));
+        assert_eq!(playlist.version(), version);
+
+        // Indices refer to the order of `to_vec`, which is the play order
+        playlist.reorder(&[2, 0, 1], version).unwrap();
+        let order: Vec<_> = playlist.to_vec().into_iter().map(|t| t.title).collect();
+        assert_eq!(order, ["c", "a", "b"]);
+        assert_eq!(playlist.pop().unwrap().title, "c");
+
+        assert!(matches!(
+            playlist.reorder(&[1, 0], version),
+            Err(ReorderError::VersionMismatch)
+        ));
+    }
+
+    #[test]
+    fn mutations_change_version() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        let mut last = playlist.version();
+        // New methods that change the queue belong in here as well
+        let mut changed = |playlist: &Playlist, method: &str| {
+            assert!(playlist.version() > last, "{} kept the version", method);
+            last = playlist.version();
+        };
+
+        playlist.push(track("a")).unwrap();
+        changed(&playlist, "push");
+        playlist.push_next(track("b")).unwrap();
+        changed(&playlist, "push_next");
+        playlist.extend(vec![track("a"), track("c")]);
+        changed(&playlist, "extend");
+        assert_eq!(playlist.dedup(), 1);
+        changed(&playlist, "dedup");
+        playlist.sort_by(|a, b| a.title.cmp(&b.title));
+        changed(&playlist, "sort_by");
+        playlist.move_entry(1, 2).unwrap();
+        changed(&playlist, "move_entry");
+        playlist.shuffle(&mut SmallRng::seed_from_u64(7));
+        changed(&playlist, "shuffle");
+        playlist.reorder(&[2, 1, 0], playlist.version()).unwrap();
+        changed(&playlist, "reorder");
+        playlist.remove(1).unwrap();
+        changed(&playlist, "remove");
+        playlist.skip(1);
+        changed(&playlist, "skip");
+        playlist.pop().unwrap();
+        changed(&playlist, "pop");
+        playlist.replace(vec![track("d"), track("e")]);
+        changed(&playlist, "replace");
+        playlist.drain_all();
+        changed(&playlist, "drain_all");
+        playlist.push(track("f")).unwrap();
+        changed(&playlist, "push");
+        playlist.clear();
+        changed(&playlist, "clear");
+    }
+
+    #[test]
+    fn dedup() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        let mut first = track("first");
+        first.webpage_url = Some(String::from("https://example.com/watch"));
+        // Same page but another stream url, like after a second lookup
+        let mut again = track("again");
+        again.webpage_url = first.webpage_url.clone();
+        playlist.extend(vec![first, track("a"), again, track("b"), track("a")]);
+
+        assert_eq!(playlist.dedup(), 2);
+        let order: Vec<_> = playlist.to_vec().into_iter().map(|t| t.title).collect();
+        assert_eq!(order, ["first", "a", "b"]);
+        assert_eq!(playlist.dedup(), 0);
+    }
+
+    #[test]
+    fn skip() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        playlist.extend(vec![track("a"), track("b"), track("c"), track("d")]);
+
+        playlist.skip(0);
+        assert_eq!(playlist.len(), 4);
+        assert_eq!(playlist.peek().unwrap().title, "a");
+
+        playlist.skip(2);
+        assert_eq!(playlist.len(), 2);
+        assert_eq!(playlist.pop().unwrap().title, "c");
+
+        playlist.skip(5);
+        assert!(playlist.is_empty());
+    }
+
+    #[test]
+    fn sort_by() {
+        let mut playlist = Playlist::new(Logger::root(Discard, o!()));
+        let mut first = track("b");
+        first.added_by = String::from("first");
+        let mut second = track("b");
+        second.added_by = String::from("second");
+        playlist.extend(vec![track("c"), first, track("a"), second]);
+
+        playlist.sort_by(|a, b| a.title.cmp(&b.title));
+        let order: Vec<_> = playlist
+            .to_vec()
+            .into_iter()
+            .map(|t| (t.title, t.added_by))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (String::from("a"), String::new()),
+                (String::from("b"), String::from("first")),
+                (String::from("b"), String::from("second")),
+                (String::from("c"), String::new()),
+            ]
+        );
+        assert_eq!(playlist.pop().unwrap().title, "a");
+    }
+}
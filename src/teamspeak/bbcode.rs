@@ -42,3 +42,75 @@ pub fn underline(text: &dyn Display) -> BbCode {
 pub fn link<'a>(text: &'a dyn Display, url: &'a str) -> BbCode<'a> {
     BbCode::Link(text, url)
 }
+
+/// Replaces `[URL]url[/URL]` and `[URL=url]label[/URL]` links with the url they point to.
+pub fn strip_links(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("[URL") {
+        let tag = &rest[start + "[URL".len()..];
+        let (href, label) = if let Some(label) = tag.strip_prefix(']') {
+            (None, label)
+        } else if let Some(attr) = tag.strip_prefix('=') {
+            match attr.find(']') {
+                Some(end) => (Some(&attr[..end]), &attr[end + 1..]),
+                None => break,
+            }
+        } else {
+            // Something like [URLX], not a link
+            result.push_str(&rest[..start + "[URL".len()]);
+            rest = tag;
+            continue;
+        };
+
+        let end = match label.find("[/URL]") {
+            Some(end) => end,
+            None => break,
+        };
+
+        result.push_str(&rest[..start]);
+        result.push_str(href.unwrap_or(&label[..end]));
+        rest = &label[end + "[/URL]".len()..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_links;
+
+    #[test]
+    fn strips_plain_links() {
+        assert_eq!(
+            strip_links("[URL]https://youtu.be/x[/URL]"),
+            "https://youtu.be/x"
+        );
+    }
+
+    #[test]
+    fn uses_href_of_labeled_links() {
+        assert_eq!(
+            strip_links("[URL=https://youtu.be/x]A song[/URL]"),
+            "https://youtu.be/x"
+        );
+    }
+
+    #[test]
+    fn keeps_surrounding_text() {
+        assert_eq!(
+            strip_links("a [URL]https://a.com[/URL] b [URL=https://b.com]b[/URL] c"),
+            "a https://a.com b https://b.com c"
+        );
+    }
+
+    #[test]
+    fn leaves_malformed_links_alone() {
+        assert_eq!(strip_links("just text"), "just text");
+        assert_eq!(strip_links("[URL=https://a.com"), "[URL=https://a.com");
+        assert_eq!(strip_links("[URL]https://a.com"), "[URL]https://a.com");
+        assert_eq!(strip_links("[URLX]"), "[URLX]");
+    }
+}
@@ -1,4 +1,5 @@
 use futures::stream::StreamExt;
+use tokio::io::AsyncWriteExt;
 use xtra::{Actor, Handler, WeakAddress};
 
 use tsclientlib::data::exts::{M2BClientEditExt, M2BClientUpdateExt};
@@ -83,7 +84,66 @@ fn get_message(event: &Event) -> Option<MusicBotMessage> {
     }
 }
 
+/// Builds a path like `Parent/Child` by following the parents of `channel`.
+///
+/// `lookup` returns the name and parent of a channel, `None` is returned
+/// if a channel is unknown or `channel` is the root channel.
+fn channel_path<'a, F>(channel: ChannelId, lookup: F) -> Option<String>
+where
+    F: Fn(ChannelId) -> Option<(&'a str, ChannelId)>,
+{
+    let mut names = Vec::new();
+    let mut current = channel;
+
+    // Channel 0 is the root channel
+    while current != ChannelId(0) {
+        let (name, parent) = lookup(current)?;
+        names.push(name);
+        current = parent;
+    }
+
+    if names.is_empty() {
+        return None;
+    }
+
+    names.reverse();
+    Some(names.join("/"))
+}
+
+/// The longest nickname TeamSpeak accepts
+const MAX_NICKNAME_LENGTH: usize = 30;
+/// How many numbered variants of a nickname are tried if it is taken
+const MAX_NICKNAME_ATTEMPTS: usize = 10;
+
+/// Returns `name` or, if it is taken, the first free variant with a numeric
+/// suffix like `name 2`. `None` is returned if all variants are taken.
+fn unique_nickname<F>(name: &str, is_taken: F) -> Option<String>
+where
+    F: Fn(&str) -> bool,
+{
+    if !is_taken(name) {
+        return Some(name.to_owned());
+    }
+
+    (2..MAX_NICKNAME_ATTEMPTS + 2)
+        .map(|n| {
+            let suffix = format!(" {}", n);
+            let length = MAX_NICKNAME_LENGTH.saturating_sub(suffix.chars().count());
+            let base: String = name.chars().take(length).collect();
+
+            format!("{}{}", base, suffix)
+        })
+        .find(|candidate| !is_taken(candidate))
+}
+
 impl TeamSpeakConnection {
+    /// Fails instead of panicking if the bot is not connected (yet).
+    fn handle(&mut self) -> anyhow::Result<&mut SyncConnectionHandle> {
+        self.handle
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to a server"))
+    }
+
     pub async fn new(logger: Logger) -> anyhow::Result<TeamSpeakConnection> {
         Ok(TeamSpeakConnection {
             handle: None,
@@ -91,7 +151,7 @@ impl TeamSpeakConnection {
         })
     }
 
-    pub fn connect_for_bot<T: Actor + Handler<MusicBotMessage>>(
+    pub async fn connect_for_bot<T: Actor + Handler<MusicBotMessage>>(
         &mut self,
         options: ConnectOptions,
         bot: WeakAddress<T>,
@@ -143,156 +203,206 @@ impl TeamSpeakConnection {
             }
         });
 
-        let mut handle = self.handle.clone();
-        tokio::spawn(async move {
-            handle
-                .as_mut()
-                .expect("connect_for_bot was called")
-                .wait_until_connected()
-                .await
-                .unwrap();
-            handle
-                .as_mut()
-                .expect("connect_for_bot was called")
-                .with_connection(|mut conn| {
-                    conn.get_state()
-                        .expect("can get state")
-                        .server
-                        .set_subscribed(true)
-                        .send(&mut conn)
-                })
-                .await
-                .and_then(|v| v)
-                .unwrap();
-        });
+        let handle = self.handle.as_mut().expect("handle was just set");
+        handle.wait_until_connected().await?;
+        handle
+            .with_connection(|mut conn| {
+                conn.get_state()?
+                    .server
+                    .set_subscribed(true)
+                    .send(&mut conn)
+            })
+            .await??;
+
+        Ok(())
+    }
+
+    /// Subscribes to every channel again, channels that were created after
+    /// connecting are not covered by the first subscription.
+    pub async fn subscribe_all(&mut self) -> anyhow::Result<()> {
+        self.handle()?
+            .with_connection(|mut conn| {
+                conn.get_state()?
+                    .server
+                    .set_subscribed(true)
+                    .send(&mut conn)
+            })
+            .await??;
 
         Ok(())
     }
 
-    pub async fn send_audio_packet(&mut self, samples: &[u8]) -> anyhow::Result<()> {
+    pub async fn send_audio_packet(
+        &mut self,
+        samples: &[u8],
+        codec: tsproto_packets::packets::CodecType,
+    ) -> anyhow::Result<()> {
         let packet =
             tsproto_packets::packets::OutAudio::new(&tsproto_packets::packets::AudioData::C2S {
                 id: 0,
-                codec: tsproto_packets::packets::CodecType::OpusMusic,
+                codec,
                 data: samples,
             });
 
-        self.handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+        let sent = self
+            .handle()?
             .with_connection(move |conn| {
                 conn.get_tsproto_client_mut()
-                    .expect("can get tsproto client")
-                    .send_packet(packet)
+                    .map(|client| client.send_packet(packet))
             })
             .await??;
+        sent?;
 
         Ok(())
     }
 
     pub async fn channel_of_user(&mut self, id: ClientId) -> anyhow::Result<Option<ChannelId>> {
         let id = self
-            .handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+            .handle()?
             .with_connection(move |conn| {
                 conn.get_state()
-                    .expect("can get state")
-                    .clients
-                    .get(&id)
-                    .map(|c| c.channel)
+                    .map(|state| state.clients.get(&id).map(|c| c.channel))
             })
-            .await?;
+            .await??;
 
         Ok(id)
     }
 
     pub async fn channel_path_of_user(&mut self, id: ClientId) -> anyhow::Result<Option<String>> {
         let path = self
-            .handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+            .handle()?
             .with_connection(move |conn| {
-                let state = conn.get_state().expect("can get state");
-
-                let channel_id = state.clients.get(&id)?.channel;
-
-                let mut channel = state.channels.get(&channel_id)?;
-
-                let mut names = vec![&channel.name[..]];
-
-                // Channel 0 is the root channel
-                while channel.parent != ChannelId(0) {
-                    names.push("/");
-                    channel = state.channels.get(&channel.parent)?;
-                    names.push(&channel.name);
-                }
+                conn.get_state().map(|state| {
+                    let channel_id = state.clients.get(&id)?.channel;
+
+                    channel_path(channel_id, |id| {
+                        state
+                            .channels
+                            .get(&id)
+                            .map(|channel| (&channel.name[..], channel.parent))
+                    })
+                })
+            })
+            .await??;
 
-                let mut path = String::new();
-                while let Some(name) = names.pop() {
-                    path.push_str(name);
-                }
+        Ok(path)
+    }
 
-                Some(path)
+    /// Full path of the channel the bot is in.
+    pub async fn my_channel_path(&mut self) -> anyhow::Result<Option<String>> {
+        let path = self
+            .handle()?
+            .with_connection(move |conn| {
+                conn.get_state().map(|state| {
+                    let channel_id = state.clients.get(&state.own_client)?.channel;
+
+                    channel_path(channel_id, |id| {
+                        state
+                            .channels
+                            .get(&id)
+                            .map(|channel| (&channel.name[..], channel.parent))
+                    })
+                })
             })
-            .await?;
+            .await??;
 
         Ok(path)
     }
 
     pub async fn current_channel(&mut self) -> anyhow::Result<Option<ChannelId>> {
         let id = self
-            .handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+            .handle()?
             .with_connection(move |conn| {
-                let state = conn.get_state().expect("can get state");
-                state.clients.get(&state.own_client).map(|c| c.channel)
+                conn.get_state()
+                    .map(|state| state.clients.get(&state.own_client).map(|c| c.channel))
             })
-            .await?;
+            .await??;
 
         Ok(id)
     }
 
     pub async fn my_id(&mut self) -> anyhow::Result<ClientId> {
         let id = self
-            .handle
-            .as_mut()
-            .expect("connect_for_bot was called")
-            .with_connection(move |conn| conn.get_state().expect("can get state").own_client)
-            .await?;
+            .handle()?
+            .with_connection(move |conn| conn.get_state().map(|state| state.own_client))
+            .await??;
 
         Ok(id)
     }
 
+    pub async fn description(&mut self) -> anyhow::Result<Option<String>> {
+        let desc = self
+            .handle()?
+            .with_connection(move |conn| {
+                conn.get_state().map(|state| {
+                    state
+                        .clients
+                        .get(&state.own_client)
+                        .map(|c| c.description.clone())
+                })
+            })
+            .await??;
+
+        Ok(desc)
+    }
+
     pub async fn user_count(&mut self, channel: ChannelId) -> anyhow::Result<u32> {
         let count = self
-            .handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+            .handle()?
             .with_connection(move |conn| {
-                let state = conn.get_state().expect("can get state");
-                let mut count = 0;
-                for client in state.clients.values() {
-                    if client.channel == channel {
-                        count += 1;
+                conn.get_state().map(|state| {
+                    let mut count = 0;
+                    for client in state.clients.values() {
+                        if client.channel == channel {
+                            count += 1;
+                        }
                     }
-                }
 
-                count
+                    count
+                })
             })
-            .await?;
+            .await??;
 
         Ok(count)
     }
 
+    /// Sets the nickname, a numeric suffix is added if another client
+    /// already uses it.
     pub async fn set_nickname(&mut self, name: String) -> anyhow::Result<()> {
-        self.handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+        let taken: Vec<String> = self
+            .handle()?
+            .with_connection(|conn| {
+                conn.get_state().map(|state| {
+                    state
+                        .clients
+                        .iter()
+                        .filter(|(id, _)| **id != state.own_client)
+                        .map(|(_, client)| client.name.clone())
+                        .collect()
+                })
+            })
+            .await??;
+
+        let name = match unique_nickname(&name, |n| taken.iter().any(|t| t == n)) {
+            Some(unique) if unique != name => {
+                info!(
+                    self.logger,
+                    "Nickname is taken, using another one";
+                    "wanted" => &name,
+                    "name" => &unique
+                );
+                unique
+            }
+            Some(unique) => unique,
+            None => {
+                warn!(self.logger, "All variants of the nickname are taken"; "name" => &name);
+                name
+            }
+        };
+
+        self.handle()?
             .with_connection(move |mut conn| {
-                conn.get_state()
-                    .expect("can get state")
+                conn.get_state()?
                     .client_update()
                     .set_name(&name)
                     .send(&mut conn)
@@ -303,34 +413,77 @@ impl TeamSpeakConnection {
     }
 
     pub async fn set_description(&mut self, desc: String) {
-        if let Err(e) = self
-            .handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+        if let Err(e) = self.try_set_description(desc).await {
+            warn!(self.logger, "Failed to set description"; "error" => %e);
+        }
+    }
+
+    async fn try_set_description(&mut self, desc: String) -> anyhow::Result<()> {
+        let sent = self
+            .handle()?
             .with_connection(move |mut conn| {
-                let state = conn.get_state().expect("can get state");
-                state
+                let state = conn.get_state()?;
+                let edit = state
                     .clients
                     .get(&state.own_client)
-                    .expect("can get myself")
-                    .edit()
-                    .set_description(&desc)
-                    .send(&mut conn)
+                    .map(|me| me.edit().set_description(&desc));
+
+                match edit {
+                    Some(edit) => edit.send(&mut conn).map(Some),
+                    None => Ok(None),
+                }
             })
-            .await
-            .and_then(|v| v)
-        {
-            warn!(self.logger, "Failed to set description"; "error" => %e);
+            .await??;
+
+        if sent.is_none() {
+            anyhow::bail!("Can not find own client");
         }
+
+        Ok(())
+    }
+
+    /// Uploads `data` as the avatar image of this client.
+    pub async fn set_avatar(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut upload = self
+            .handle()?
+            .upload_file(
+                ChannelId(0),
+                "/avatar",
+                None,
+                data.len() as u64,
+                true,
+                false,
+            )
+            .await?;
+        upload.stream.write_all(data).await?;
+        upload.stream.shutdown().await?;
+
+        // Other clients only fetch the new image if the hash changes
+        let hash = format!("{:x}", md5::compute(data));
+        self.set_avatar_hash(hash).await
+    }
+
+    pub async fn clear_avatar(&mut self) -> anyhow::Result<()> {
+        self.set_avatar_hash(String::new()).await
+    }
+
+    async fn set_avatar_hash(&mut self, hash: String) -> anyhow::Result<()> {
+        self.handle()?
+            .with_connection(move |mut conn| {
+                conn.get_state()?
+                    .client_update()
+                    .set_avatar_hash(&hash)
+                    .send(&mut conn)
+            })
+            .await??;
+
+        Ok(())
     }
 
     pub async fn send_message_to_channel(&mut self, text: String) -> anyhow::Result<()> {
-        self.handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+        self.handle()?
             .with_connection(move |mut conn| {
-                conn.get_state()
-                    .expect("can get state")
+                conn.get_state()?
                     .send_message(MessageTarget::Channel, &text)
                     .send(&mut conn)
             })
@@ -344,12 +497,9 @@ impl TeamSpeakConnection {
         client: ClientId,
         text: String,
     ) -> anyhow::Result<()> {
-        self.handle
-            .as_mut()
-            .expect("connect_for_bot was called")
+        self.handle()?
             .with_connection(move |mut conn| {
-                conn.get_state()
-                    .expect("can get state")
+                conn.get_state()?
                     .send_message(MessageTarget::Client(client), &text)
                     .send(&mut conn)
             })
@@ -362,12 +512,110 @@ impl TeamSpeakConnection {
         let opt = DisconnectOptions::new()
             .reason(Reason::Clientdisconnect)
             .message(reason);
-        self.handle
-            .as_mut()
-            .expect("connect_for_bot was called")
-            .disconnect(opt)
-            .await?;
+        self.handle()?.disconnect(opt).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use slog::{o, Discard, Logger};
+    use tsclientlib::{ChannelId, ClientId};
+    use tsproto_packets::packets::CodecType;
+
+    use super::{channel_path, unique_nickname, TeamSpeakConnection};
+
+    fn path_in(channels: &[(u64, &'static str, u64)], start: u64) -> Option<String> {
+        let channels: HashMap<_, _> = channels
+            .iter()
+            .map(|&(id, name, parent)| (ChannelId(id), (name, ChannelId(parent))))
+            .collect();
+
+        channel_path(ChannelId(start), |id| channels.get(&id).copied())
+    }
+
+    #[test]
+    fn flat_channel() {
+        let channels = [(1, "Lobby", 0), (2, "Music", 0)];
+
+        assert_eq!(path_in(&channels, 2).as_deref(), Some("Music"));
+    }
+
+    #[test]
+    fn nested_channel() {
+        let channels = [
+            (1, "Games", 0),
+            (2, "Strategy", 1),
+            (3, "Lobby", 0),
+            (4, "Room 1", 2),
+        ];
+
+        assert_eq!(
+            path_in(&channels, 4).as_deref(),
+            Some("Games/Strategy/Room 1")
+        );
+    }
+
+    #[test]
+    fn root_channel() {
+        let channels = [(1, "Lobby", 0)];
+
+        assert_eq!(path_in(&channels, 0), None);
+    }
+
+    #[test]
+    fn unknown_channel() {
+        let channels = [(1, "Lobby", 0), (2, "Orphan", 5)];
+
+        assert_eq!(path_in(&channels, 3), None);
+        assert_eq!(path_in(&channels, 2), None);
+    }
+
+    #[test]
+    fn nickname_suffix() {
+        let taken = ["MusicBot", "MusicBot 2"];
+        let is_taken = |name: &str| taken.contains(&name);
+
+        assert_eq!(unique_nickname("Other", is_taken).as_deref(), Some("Other"));
+        assert_eq!(
+            unique_nickname("MusicBot", is_taken).as_deref(),
+            Some("MusicBot 3")
+        );
+    }
+
+    #[test]
+    fn nickname_suffix_fits_limit() {
+        let long = "a".repeat(30);
+        let unique = unique_nickname(&long, |name| name == long).unwrap();
+
+        assert_eq!(unique.chars().count(), 30);
+        assert!(unique.ends_with(" 2"));
+    }
+
+    #[tokio::test]
+    async fn closed_handle_returns_errors() {
+        let mut ts = TeamSpeakConnection::new(Logger::root(Discard, o!()))
+            .await
+            .unwrap();
+
+        assert!(ts.my_id().await.is_err());
+        assert!(ts.current_channel().await.is_err());
+        assert!(ts.channel_of_user(ClientId(1)).await.is_err());
+        assert!(ts.send_message_to_channel(String::new()).await.is_err());
+        assert!(ts.set_nickname(String::from("MusicBot")).await.is_err());
+        assert!(ts
+            .send_audio_packet(&[0; 4], CodecType::OpusMusic)
+            .await
+            .is_err());
+        // Only logs the error
+        ts.set_description(String::from("Description")).await;
+    }
+
+    #[test]
+    fn nickname_attempts_are_bounded() {
+        assert_eq!(unique_nickname("MusicBot", |_| true), None);
+    }
+}
@@ -1,26 +1,37 @@
 use std::time::Duration;
 
 use actix_slog::StructuredLogger;
-use actix_web::{get, post, web, App, HttpServer, Responder};
+use actix_web::{get, http::header, post, web, App, HttpResponse, HttpServer, Responder};
 use askama_actix::{Template, TemplateIntoResponse};
 use serde::{Deserialize, Serialize};
 use slog::Logger;
 use xtra::WeakAddress;
 
 use crate::bot::MasterBot;
+use crate::command::{Command, VolumeChange};
 use crate::youtube_dl::AudioMetadata;
 
 mod api;
+mod bot_control;
 mod bot_data;
+mod cors;
 mod default;
+mod error;
 mod front_end_cookie;
+mod mobile;
 mod tmtu;
+pub use api::UploadConfig;
+pub use bot_control::*;
 pub use bot_data::*;
+use cors::Cors;
+use error::WebError;
 use front_end_cookie::FrontEnd;
 
 pub struct WebServerArgs {
     pub domain: String,
     pub bind_address: String,
+    pub cors_origins: Vec<String>,
+    pub upload: UploadConfig,
     pub bot: WeakAddress<MasterBot>,
 }
 
@@ -28,29 +39,74 @@ pub struct WebServerArgs {
 pub async fn start(args: WebServerArgs, logger: Logger) -> std::io::Result<()> {
     let bot = args.bot;
     let bind_address = args.bind_address;
+    let cors_origins = args.cors_origins;
+    let upload = args.upload;
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .data(bot.clone())
+            .data(upload.clone())
+            .wrap(Cors::new(cors_origins.clone()))
             .wrap(StructuredLogger::new(logger.clone()))
             .service(index)
             .service(get_bot)
+            .service(post_bot_volume)
+            .service(post_bot_control)
             .service(post_front_end)
             .service(
                 web::scope("/api")
                     .service(api::get_bot_list)
-                    .service(api::get_bot),
+                    .service(api::get_bot)
+                    .service(api::get_recent)
+                    .service(api::get_bot_events)
+                    .service(api::get_bot_volume)
+                    .service(api::put_bot_volume)
+                    .service(api::get_bot_title)
+                    .service(api::post_upload)
+                    .service(api::get_art)
+                    .service(api::put_playlist),
             )
             .service(web::scope("/docs").service(get_api_docs))
             .service(actix_files::Files::new("/static", "web_server/static/"))
-    })
-    .bind(bind_address)?
-    .run()
-    .await?;
+    });
+
+    let server = match unix_socket_path(&bind_address) {
+        #[cfg(unix)]
+        Some(path) => {
+            // A socket left behind by a previous run would make the bind fail
+            if is_socket(path) {
+                std::fs::remove_file(path)?;
+            }
+            server.bind_uds(path)?
+        }
+        _ => server.bind(bind_address)?,
+    };
+
+    server.run().await?;
 
     Ok(())
 }
 
+/// Bind addresses starting with `unix:` or `/` are paths to a Unix domain socket.
+fn unix_socket_path(bind_address: &str) -> Option<&str> {
+    if let Some(path) = bind_address.strip_prefix("unix:") {
+        Some(path)
+    } else if bind_address.starts_with('/') {
+        Some(bind_address)
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn is_socket(path: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path)
+        .map(|m| m.file_type().is_socket())
+        .unwrap_or(false)
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct FrontEndForm {
@@ -68,16 +124,32 @@ pub struct BotData {
     pub state: crate::bot::State,
     pub volume: f64,
     pub position: Option<Duration>,
+    /// Time until the playlist ends, this is a lower bound because tracks
+    /// of unknown length are not counted
+    pub remaining: Option<Duration>,
     pub currently_playing: Option<AudioMetadata>,
     pub playlist: Vec<AudioMetadata>,
+    pub playlist_version: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentTrack {
+    pub bot: String,
+    /// Channel path of the bot when the track started
+    pub channel: Option<String>,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+    pub track: AudioMetadata,
 }
 
 #[get("/")]
-async fn index(bot: web::Data<WeakAddress<MasterBot>>, front: FrontEnd) -> impl Responder {
-    match front {
-        FrontEnd::Default => default::index(bot).await,
-        FrontEnd::Tmtu => tmtu::index(bot).await,
-    }
+async fn index(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    front: FrontEnd,
+) -> Result<HttpResponse, WebError> {
+    let bot_names = bot.send(BotNameListRequest).await?;
+
+    render_front_end(front, bot_names, None)
 }
 
 #[get("/bot/{name}")]
@@ -85,13 +157,95 @@ async fn get_bot(
     bot: web::Data<WeakAddress<MasterBot>>,
     name: web::Path<String>,
     front: FrontEnd,
-) -> impl Responder {
-    match front {
-        FrontEnd::Default => default::get_bot(bot, name.into_inner()).await,
-        FrontEnd::Tmtu => tmtu::get_bot(bot, name.into_inner()).await,
+) -> Result<HttpResponse, WebError> {
+    let bot_names = bot.send(BotNameListRequest).await?;
+
+    match bot.send(BotDataRequest(name.into_inner())).await? {
+        Some(bot) => render_front_end(front, bot_names, Some(bot)),
+        None => Ok(HttpResponse::Found().header(header::LOCATION, "/").finish()),
     }
 }
 
+/// The front ends show the same data and only differ in their templates.
+fn render_front_end(
+    front: FrontEnd,
+    bot_names: Vec<String>,
+    bot: Option<BotData>,
+) -> Result<HttpResponse, WebError> {
+    let response = match front {
+        FrontEnd::Default => default::render(bot_names, bot),
+        FrontEnd::Tmtu => tmtu::render(bot_names, bot),
+        FrontEnd::Mobile => mobile::render(bot_names, bot),
+    };
+
+    response.map_err(|e| WebError::Internal(e.to_string()))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Control {
+    Play,
+    Pause,
+    Stop,
+    Next,
+}
+
+#[post("/bot/{name}/{control}")]
+async fn post_bot_control(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    path: web::Path<(String, Control)>,
+) -> Result<HttpResponse, WebError> {
+    let (name, control) = path.into_inner();
+    let command = match control {
+        Control::Play => Command::Play,
+        Control::Pause => Command::Pause,
+        Control::Stop => Command::Stop,
+        Control::Next => Command::Next { count: None },
+    };
+
+    execute_bot_command(&bot, name, command).await
+}
+
+#[derive(Deserialize)]
+struct VolumeForm {
+    volume: String,
+}
+
+#[post("/bot/{name}/volume")]
+async fn post_bot_volume(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    name: web::Path<String>,
+    form: web::Form<VolumeForm>,
+) -> Result<HttpResponse, WebError> {
+    let volume = match form.volume.parse::<VolumeChange>() {
+        Ok(volume) => volume,
+        Err(_) => return Ok(HttpResponse::BadRequest().finish()),
+    };
+
+    execute_bot_command(&bot, name.into_inner(), Command::Volume { volume }).await
+}
+
+async fn execute_bot_command(
+    bot: &WeakAddress<MasterBot>,
+    name: String,
+    command: Command,
+) -> Result<HttpResponse, WebError> {
+    let request = BotCommandRequest {
+        name: name.clone(),
+        command,
+    };
+
+    let response = if bot.send(request).await?.is_some() {
+        HttpResponse::Found()
+            .header(header::LOCATION, format!("/bot/{}", name))
+            .finish()
+    } else {
+        HttpResponse::Found().header(header::LOCATION, "/").finish()
+    };
+
+    Ok(response)
+}
+
 #[derive(Template)]
 #[template(path = "docs/api.htm")]
 struct ApiDocsTemplate;
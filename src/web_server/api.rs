@@ -1,49 +1,504 @@
-use actix_web::{get, web, HttpResponse, Responder, ResponseError};
-use derive_more::Display;
-use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use actix_multipart::Multipart;
+use actix_web::{get, http::header, http::StatusCode, post, put, web, HttpResponse, ResponseError};
+use futures::{stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use xtra::WeakAddress;
 
-use crate::web_server::{BotDataListRequest, BotDataRequest};
+use crate::bot::{AudioLocation, BotCreationError, QueueLocked, State};
+use crate::playlist::ReorderError;
+use crate::web_server::error::WebError;
+use crate::web_server::{
+    ArtRequest, BotAddAudioRequest, BotData, BotDataListRequest, BotDataRequest,
+    BotNameListRequest, BotReorderRequest, BotSetVolumeRequest, BotVolumeUpdatesRequest,
+    RecentTrack, RecentTracksRequest,
+};
 use crate::MasterBot;
 
 #[get("/bots")]
-pub async fn get_bot_list(bot: web::Data<WeakAddress<MasterBot>>) -> impl Responder {
-    let bot_datas = bot.send(BotDataListRequest).await.unwrap();
+pub async fn get_bot_list(
+    bot: web::Data<WeakAddress<MasterBot>>,
+) -> Result<web::Json<Vec<BotData>>, WebError> {
+    let bot_datas = bot.send(BotDataListRequest).await?;
 
-    web::Json(bot_datas)
+    Ok(web::Json(bot_datas))
+}
+
+/// Tracks recently started by any bot, the newest one first.
+#[get("/recent")]
+pub async fn get_recent(
+    bot: web::Data<WeakAddress<MasterBot>>,
+) -> Result<web::Json<Vec<RecentTrack>>, WebError> {
+    let tracks = bot.send(RecentTracksRequest).await?;
+
+    Ok(web::Json(tracks))
 }
 
 #[get("/bots/{name}")]
 pub async fn get_bot(
     bot: web::Data<WeakAddress<MasterBot>>,
     name: web::Path<String>,
-) -> impl Responder {
-    if let Some(bot_data) = bot.send(BotDataRequest(name.into_inner())).await.unwrap() {
+) -> Result<web::Json<BotData>, WebError> {
+    if let Some(bot_data) = bot.send(BotDataRequest(name.into_inner())).await? {
         Ok(web::Json(bot_data))
     } else {
-        Err(ApiErrorKind::NotFound)
+        Err(WebError::NotFound)
     }
 }
 
-#[derive(Serialize)]
-struct ApiError {
-    error: String,
-    description: String,
+/// Plain text for the document title of a page showing the bot.
+#[get("/bots/{name}/title")]
+pub async fn get_bot_title(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, WebError> {
+    match bot.send(BotDataRequest(name.into_inner())).await? {
+        Some(bot_data) => {
+            let title = document_title(
+                &bot_data.name,
+                bot_data.state,
+                bot_data
+                    .currently_playing
+                    .as_ref()
+                    .map(|t| t.title.as_str()),
+            );
+            Ok(HttpResponse::Ok()
+                .content_type("text/plain; charset=utf-8")
+                .body(title))
+        }
+        None => Err(WebError::NotFound),
+    }
 }
 
-#[derive(Debug, Display)]
-enum ApiErrorKind {
-    #[display(fmt = "Not Found")]
-    NotFound,
+/// "▶ Title — BotName" while playing, "⏸ Title — BotName" while paused
+/// and only the name of the bot otherwise.
+fn document_title(name: &str, state: State, track: Option<&str>) -> String {
+    let symbol = match state {
+        State::Playing => "\u{25b6}",
+        State::Paused => "\u{23f8}",
+        State::Stopped | State::EndOfStream => return name.to_owned(),
+    };
+
+    match track {
+        Some(track) => format!("{} {} \u{2014} {}", symbol, track, name),
+        None => name.to_owned(),
+    }
 }
 
-impl ResponseError for ApiErrorKind {
-    fn error_response(&self) -> HttpResponse {
-        match *self {
-            ApiErrorKind::NotFound => HttpResponse::NotFound().json(ApiError {
-                error: self.to_string(),
-                description: String::from("The requested resource was not found"),
-            }),
+#[derive(Serialize, Deserialize)]
+pub struct Volume {
+    volume: f64,
+}
+
+#[get("/bots/{name}/volume")]
+pub async fn get_bot_volume(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    name: web::Path<String>,
+) -> Result<web::Json<Volume>, WebError> {
+    match bot.send(BotDataRequest(name.into_inner())).await? {
+        Some(bot_data) => Ok(web::Json(Volume {
+            volume: bot_data.volume,
+        })),
+        None => Err(WebError::NotFound),
+    }
+}
+
+#[put("/bots/{name}/volume")]
+pub async fn put_bot_volume(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    name: web::Path<String>,
+    volume: web::Json<Volume>,
+) -> Result<HttpResponse, WebError> {
+    let volume = volume.volume;
+    if !(0.0..=1.0).contains(&volume) {
+        return Err(WebError::BadRequest(String::from(
+            "The volume has to be between 0.0 and 1.0",
+        )));
+    }
+
+    let request = BotSetVolumeRequest {
+        name: name.into_inner(),
+        volume,
+    };
+
+    match bot.send(request).await? {
+        Some(Ok(())) => Ok(HttpResponse::NoContent().finish()),
+        Some(Err(e)) => Err(WebError::Internal(e.to_string())),
+        None => Err(WebError::NotFound),
+    }
+}
+
+/// File types that can be uploaded, the same ones local files are searched for.
+const UPLOAD_EXTENSIONS: &[&str] = &["mp3", "flac"];
+
+#[derive(Clone)]
+pub struct UploadConfig {
+    pub music_root: Option<PathBuf>,
+    /// Directory inside of `music_root` uploads are saved to, disabled if unset
+    pub upload_dir: Option<PathBuf>,
+    /// Maximum size of an uploaded file in bytes
+    pub max_size: usize,
+}
+
+/// Saves an uploaded audio file into the upload directory and queues it.
+#[post("/bots/{name}/upload")]
+pub async fn post_upload(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    upload: web::Data<UploadConfig>,
+    name: web::Path<String>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, WebError> {
+    let (music_root, upload_dir) = match (&upload.music_root, &upload.upload_dir) {
+        (Some(music_root), Some(upload_dir)) => (music_root, upload_dir),
+        _ => return Err(WebError::Forbidden(String::from("Uploads are disabled"))),
+    };
+
+    // Do not save files for a bot that does not exist
+    let name = name.into_inner();
+    if !bot.send(BotNameListRequest).await?.contains(&name) {
+        return Err(WebError::NotFound);
+    }
+
+    let bad_request = |e: actix_multipart::MultipartError| WebError::BadRequest(e.to_string());
+    while let Some(mut field) = payload.try_next().await.map_err(bad_request)? {
+        let file_name = match field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename().map(String::from))
+        {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        let file_name = sanitize_file_name(&file_name).ok_or_else(|| {
+            WebError::BadRequest(format!(
+                "Only files ending in {} can be uploaded",
+                UPLOAD_EXTENSIONS.join(", ")
+            ))
+        })?;
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field.try_next().await.map_err(bad_request)? {
+            if data.len() + chunk.len() > upload.max_size {
+                return Err(WebError::PayloadTooLarge);
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        let dir = music_root.join(upload_dir);
+        let saved_name = web::block(move || save_upload(&dir, &file_name, &data))
+            .await
+            .map_err(|e| WebError::Internal(e.to_string()))?;
+
+        let request = BotAddAudioRequest {
+            name,
+            location: AudioLocation::Path(upload_dir.join(saved_name)),
+            user: String::from("web"),
+        };
+
+        return match bot.send(request).await? {
+            Some(Ok(())) => Ok(HttpResponse::NoContent().finish()),
+            Some(Err(e)) if e.is::<QueueLocked>() => Err(WebError::Forbidden(e.to_string())),
+            Some(Err(e)) => Err(WebError::BadRequest(e.to_string())),
+            None => Err(WebError::NotFound),
+        };
+    }
+
+    Err(WebError::BadRequest(String::from(
+        "The request did not contain a file",
+    )))
+}
+
+/// Strips directories and unusual characters from an uploaded file name,
+/// `None` is returned if it is not an audio file.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    // Browsers on Windows might send the full path
+    let name = name.rsplit(|c| c == '/' || c == '\\').next()?;
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "-_. ()".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // Leading dots would create hidden files
+    let name = name.trim().trim_start_matches('.');
+
+    let extension = Path::new(name).extension()?.to_str()?.to_lowercase();
+    if !UPLOAD_EXTENSIONS.contains(&extension.as_str()) {
+        return None;
+    }
+
+    Some(name.to_owned())
+}
+
+/// Writes the file without replacing existing ones, a number is added to the
+/// name if it is taken. Returns the name the file was saved as.
+fn save_upload(dir: &Path, file_name: &str, data: &[u8]) -> std::io::Result<String> {
+    std::fs::create_dir_all(dir)?;
+
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+
+    for attempt in 0..100 {
+        let name = match attempt {
+            0 => file_name.to_owned(),
+            n => format!("{} ({}).{}", stem, n, extension),
+        };
+
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(&name))
+        {
+            Ok(mut file) => {
+                file.write_all(data)?;
+                return Ok(name);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
         }
     }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        "Too many files with the same name",
+    ))
+}
+
+#[get("/art/{id}")]
+pub async fn get_art(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, WebError> {
+    match bot.send(ArtRequest(id.into_inner())).await? {
+        // The id is derived from the content so it never changes
+        Some(art) => Ok(HttpResponse::Ok()
+            .content_type(art.mime_type.as_str())
+            .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+            .body(art.data.clone())),
+        None => Err(WebError::NotFound),
+    }
+}
+
+/// Streams changes of the bot as server-sent events.
+#[get("/bots/{name}/events")]
+pub async fn get_bot_events(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, WebError> {
+    let updates = match bot.send(BotVolumeUpdatesRequest(name.into_inner())).await? {
+        Some(updates) => updates,
+        None => return Err(WebError::NotFound),
+    };
+
+    let events = stream::unfold(updates, |mut updates| async move {
+        // Ends the stream once the bot is gone
+        updates.changed().await.ok()?;
+        let event = format!("event: volume\ndata: {}\n\n", *updates.borrow());
+
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(event)), updates))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(events))
+}
+
+#[derive(Deserialize)]
+pub struct PlaylistOrder {
+    version: u64,
+    order: Vec<usize>,
+}
+
+#[put("/bots/{name}/playlist")]
+pub async fn put_playlist(
+    bot: web::Data<WeakAddress<MasterBot>>,
+    name: web::Path<String>,
+    order: web::Json<PlaylistOrder>,
+) -> Result<HttpResponse, WebError> {
+    let order = order.into_inner();
+    let request = BotReorderRequest {
+        name: name.into_inner(),
+        order: order.order,
+        version: order.version,
+    };
+
+    match bot.send(request).await? {
+        Some(Ok(())) => Ok(HttpResponse::NoContent().finish()),
+        Some(Err(e @ ReorderError::VersionMismatch))
+        | Some(Err(e @ ReorderError::LengthMismatch)) => Err(WebError::Conflict(e.to_string())),
+        Some(Err(e @ ReorderError::InvalidOrder)) => Err(WebError::BadRequest(e.to_string())),
+        None => Err(WebError::NotFound),
+    }
+}
+
+impl ResponseError for BotCreationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            BotCreationError::UnfoundUser => StatusCode::NOT_FOUND,
+            BotCreationError::MasterChannel(_) | BotCreationError::MultipleBots(_) => {
+                StatusCode::CONFLICT
+            }
+            BotCreationError::OutOfNames | BotCreationError::OutOfIdentities => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+    use slog::{o, Discard, Logger};
+    use tsclientlib::Identity;
+    use xtra::{spawn::Tokio, Actor, Address};
+
+    use super::*;
+    use crate::bot::MasterArgs;
+
+    const CONFIG: &str = r#"
+        address = "localhost"
+        volume = 0.5
+        domain = "localhost"
+        bind_address = "127.0.0.1:0"
+        webserver_enable = true
+        names = ["MusicBot"]
+    "#;
+
+    /// Spawns a master that is not connected to any server, the actors run
+    /// on tokio 1 while actix-web brings its own runtime.
+    fn spawn_master(runtime: &tokio::runtime::Runtime) -> Address<MasterBot> {
+        let mut args: MasterArgs = toml::from_str(CONFIG).unwrap();
+        args.id = Some(Identity::create());
+        args.ids = Some(Vec::new());
+        let logger = Logger::root(Discard, o!());
+
+        runtime.block_on(async {
+            MasterBot::new(args, logger)
+                .await
+                .unwrap()
+                .create(None)
+                .spawn(&mut Tokio::Global)
+        })
+    }
+
+    async fn get(master: &Address<MasterBot>, uri: &str) -> (StatusCode, Value) {
+        let mut app = test::init_service(
+            App::new().data(master.downgrade()).service(
+                web::scope("/api")
+                    .service(get_bot_list)
+                    .service(get_bot)
+                    .service(get_bot_volume)
+                    .service(put_bot_volume)
+                    .service(get_bot_title)
+                    .service(get_art),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        let status = res.status();
+        let body = test::read_body(res).await;
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn api_contract() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let master = spawn_master(&runtime);
+
+        actix_rt::System::new("api-test").block_on(async {
+            let (status, body) = get(&master, "/api/bots").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, json!([]));
+
+            let (status, body) = get(&master, "/api/bots/MusicBot").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(
+                body,
+                json!({
+                    "error": "Not Found",
+                    "description": "The requested resource was not found",
+                })
+            );
+
+            let (status, body) = get(&master, "/api/bots/MusicBot/volume").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body["error"], "Not Found");
+
+            let (status, body) = get(&master, "/api/bots/MusicBot/title").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body["error"], "Not Found");
+
+            let (status, body) = get(&master, "/api/art/unknown").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body["error"], "Not Found");
+        });
+    }
+
+    #[test]
+    fn document_titles() {
+        assert_eq!(
+            document_title("MusicBot", State::Playing, Some("Song")),
+            "\u{25b6} Song \u{2014} MusicBot"
+        );
+        assert_eq!(
+            document_title("MusicBot", State::Paused, Some("Song")),
+            "\u{23f8} Song \u{2014} MusicBot"
+        );
+        assert_eq!(document_title("MusicBot", State::Stopped, None), "MusicBot");
+    }
+
+    #[test]
+    fn upload_file_names() {
+        assert_eq!(
+            sanitize_file_name("../../etc/song.mp3").as_deref(),
+            Some("song.mp3")
+        );
+        assert_eq!(
+            sanitize_file_name("C:\\Music\\Song (Live).FLAC").as_deref(),
+            Some("Song (Live).FLAC")
+        );
+        assert_eq!(
+            sanitize_file_name("a;b$c.mp3").as_deref(),
+            Some("a_b_c.mp3")
+        );
+        assert_eq!(sanitize_file_name("..mp3"), None);
+        assert_eq!(
+            sanitize_file_name("...hidden.mp3").as_deref(),
+            Some("hidden.mp3")
+        );
+        assert_eq!(sanitize_file_name("script.sh"), None);
+        assert_eq!(sanitize_file_name("music/"), None);
+    }
+
+    #[test]
+    fn uploads_do_not_replace_files() {
+        let dir = std::env::temp_dir().join(format!("pokebot-upload-{}", std::process::id()));
+
+        assert_eq!(save_upload(&dir, "a.mp3", b"1").unwrap(), "a.mp3");
+        assert_eq!(save_upload(&dir, "a.mp3", b"2").unwrap(), "a (1).mp3");
+        assert_eq!(std::fs::read(dir.join("a.mp3")).unwrap(), b"1");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
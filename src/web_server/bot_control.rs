@@ -0,0 +1,103 @@
+use async_trait::async_trait;
+
+use tokio::sync::watch;
+use xtra::{Context, Handler, Message};
+
+use crate::bot::{AudioLocation, MasterBot};
+use crate::command::Command;
+use crate::playlist::ReorderError;
+
+pub struct BotCommandRequest {
+    pub name: String,
+    pub command: Command,
+}
+
+impl Message for BotCommandRequest {
+    type Result = Option<()>;
+}
+
+#[async_trait]
+impl Handler<BotCommandRequest> for MasterBot {
+    async fn handle(&mut self, r: BotCommandRequest, _: &mut Context<Self>) -> Option<()> {
+        self.bot_command(r.name, r.command).await
+    }
+}
+
+pub struct BotVolumeUpdatesRequest(pub String);
+
+impl Message for BotVolumeUpdatesRequest {
+    type Result = Option<watch::Receiver<f64>>;
+}
+
+#[async_trait]
+impl Handler<BotVolumeUpdatesRequest> for MasterBot {
+    async fn handle(
+        &mut self,
+        r: BotVolumeUpdatesRequest,
+        _: &mut Context<Self>,
+    ) -> Option<watch::Receiver<f64>> {
+        self.bot_volume_updates(r.0).await
+    }
+}
+
+pub struct BotAddAudioRequest {
+    pub name: String,
+    pub location: AudioLocation,
+    pub user: String,
+}
+
+impl Message for BotAddAudioRequest {
+    type Result = Option<anyhow::Result<()>>;
+}
+
+#[async_trait]
+impl Handler<BotAddAudioRequest> for MasterBot {
+    async fn handle(
+        &mut self,
+        r: BotAddAudioRequest,
+        _: &mut Context<Self>,
+    ) -> Option<anyhow::Result<()>> {
+        self.bot_add_audio(r.name, r.location, r.user).await
+    }
+}
+
+pub struct BotSetVolumeRequest {
+    pub name: String,
+    pub volume: f64,
+}
+
+impl Message for BotSetVolumeRequest {
+    type Result = Option<anyhow::Result<()>>;
+}
+
+#[async_trait]
+impl Handler<BotSetVolumeRequest> for MasterBot {
+    async fn handle(
+        &mut self,
+        r: BotSetVolumeRequest,
+        _: &mut Context<Self>,
+    ) -> Option<anyhow::Result<()>> {
+        self.bot_set_volume(r.name, r.volume).await
+    }
+}
+
+pub struct BotReorderRequest {
+    pub name: String,
+    pub order: Vec<usize>,
+    pub version: u64,
+}
+
+impl Message for BotReorderRequest {
+    type Result = Option<Result<(), ReorderError>>;
+}
+
+#[async_trait]
+impl Handler<BotReorderRequest> for MasterBot {
+    async fn handle(
+        &mut self,
+        r: BotReorderRequest,
+        _: &mut Context<Self>,
+    ) -> Option<Result<(), ReorderError>> {
+        self.bot_reorder(r.name, r.order, r.version).await
+    }
+}
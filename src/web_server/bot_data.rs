@@ -1,9 +1,12 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 
 use xtra::{Context, Handler, Message};
 
+use crate::art_cache::Art;
 use crate::bot::MasterBot;
-use crate::web_server::BotData;
+use crate::web_server::{BotData, RecentTrack};
 
 pub struct BotNameListRequest;
 
@@ -45,3 +48,29 @@ impl Handler<BotDataRequest> for MasterBot {
         self.bot_data(name).await
     }
 }
+
+pub struct RecentTracksRequest;
+
+impl Message for RecentTracksRequest {
+    type Result = Vec<RecentTrack>;
+}
+
+#[async_trait]
+impl Handler<RecentTracksRequest> for MasterBot {
+    async fn handle(&mut self, _: RecentTracksRequest, _: &mut Context<Self>) -> Vec<RecentTrack> {
+        self.recent_tracks()
+    }
+}
+
+pub struct ArtRequest(pub String);
+
+impl Message for ArtRequest {
+    type Result = Option<Arc<Art>>;
+}
+
+#[async_trait]
+impl Handler<ArtRequest> for MasterBot {
+    async fn handle(&mut self, r: ArtRequest, _: &mut Context<Self>) -> Option<Arc<Art>> {
+        self.art(&r.0)
+    }
+}
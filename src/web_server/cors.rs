@@ -0,0 +1,120 @@
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderValue, Method},
+    Error, HttpResponse,
+};
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+
+/// Adds CORS headers for requests coming from one of the configured origins.
+///
+/// Without any configured origins no headers are added, so browsers only
+/// allow same-origin requests.
+pub struct Cors {
+    allowed_origins: Rc<Vec<String>>,
+}
+
+impl Cors {
+    pub fn new(allowed_origins: Vec<String>) -> Self {
+        Self {
+            allowed_origins: Rc::new(allowed_origins),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware {
+            service,
+            allowed_origins: self.allowed_origins.clone(),
+        })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    allowed_origins: Rc<Vec<String>>,
+}
+
+impl<S> CorsMiddleware<S> {
+    fn allowed_origin(&self, req: &ServiceRequest) -> Option<HeaderValue> {
+        let origin = req.headers().get(header::ORIGIN)?;
+        let value = origin.to_str().ok()?;
+
+        if self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == value)
+        {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+        Ready<Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let origin = match self.allowed_origin(&req) {
+            Some(origin) => origin,
+            None => return Either::Left(Box::pin(self.service.call(req))),
+        };
+
+        let is_preflight = req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if is_preflight {
+            let res = HttpResponse::NoContent()
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, PUT")
+                .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "content-type")
+                .header(header::VARY, "Origin")
+                .finish()
+                .into_body();
+
+            return Either::Right(ok(req.into_response(res)));
+        }
+
+        let fut = self.service.call(req);
+        Either::Left(Box::pin(async move {
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+
+            Ok(res)
+        }))
+    }
+}
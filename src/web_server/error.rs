@@ -0,0 +1,89 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use derive_more::Display;
+use serde::Serialize;
+
+/// Body of every error response of the API.
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    description: String,
+}
+
+/// Errors returned by the handlers of the API, each one maps to a status code.
+#[derive(Debug, Display)]
+pub enum WebError {
+    #[display(fmt = "Not Found")]
+    NotFound,
+    #[display(fmt = "Bad Request")]
+    BadRequest(String),
+    #[display(fmt = "Forbidden")]
+    Forbidden(String),
+    #[display(fmt = "Conflict")]
+    Conflict(String),
+    #[display(fmt = "Payload Too Large")]
+    PayloadTooLarge,
+    /// The master bot is not running anymore
+    #[display(fmt = "Service Unavailable")]
+    ServiceUnavailable,
+    #[display(fmt = "Internal Server Error")]
+    Internal(String),
+}
+
+impl WebError {
+    fn description(&self) -> String {
+        match self {
+            WebError::NotFound => String::from("The requested resource was not found"),
+            WebError::PayloadTooLarge => String::from("The file is larger than allowed"),
+            WebError::ServiceUnavailable => String::from("The bot is shutting down"),
+            WebError::BadRequest(description)
+            | WebError::Forbidden(description)
+            | WebError::Conflict(description)
+            | WebError::Internal(description) => description.clone(),
+        }
+    }
+}
+
+impl From<xtra::Disconnected> for WebError {
+    fn from(_: xtra::Disconnected) -> Self {
+        WebError::ServiceUnavailable
+    }
+}
+
+impl ResponseError for WebError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebError::NotFound => StatusCode::NOT_FOUND,
+            WebError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            WebError::Forbidden(_) => StatusCode::FORBIDDEN,
+            WebError::Conflict(_) => StatusCode::CONFLICT,
+            WebError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            WebError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            WebError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.to_string(),
+            description: self.description(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, ResponseError};
+
+    use super::WebError;
+
+    #[test]
+    fn status_codes() {
+        let error = WebError::from(xtra::Disconnected);
+        assert_eq!(error.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.to_string(), "Service Unavailable");
+
+        let error = WebError::Conflict(String::from("Playlist changed"));
+        assert_eq!(error.status_code(), StatusCode::CONFLICT);
+        assert_eq!(error.description(), "Playlist changed");
+    }
+}
@@ -12,6 +12,7 @@ use serde::Deserialize;
 pub enum FrontEnd {
     Default,
     Tmtu,
+    Mobile,
 }
 
 impl FrontEnd {
@@ -21,6 +22,7 @@ impl FrontEnd {
         let name = match self {
             FrontEnd::Default => "default",
             FrontEnd::Tmtu => "tmtu",
+            FrontEnd::Mobile => "mobile",
         };
 
         format!("{}={}", Self::COOKIE_NAME, name)
@@ -41,6 +43,7 @@ impl FromRequest for FrontEnd {
                         match split.next() {
                             Some("default") => return ok(FrontEnd::Default),
                             Some("tmtu") => return ok(FrontEnd::Tmtu),
+                            Some("mobile") => return ok(FrontEnd::Mobile),
                             _ => (),
                         }
                     }
@@ -0,0 +1,15 @@
+use actix_web::{Error, HttpResponse};
+use askama_actix::{Template, TemplateIntoResponse};
+
+use crate::web_server::{filters, BotData};
+
+#[derive(Template)]
+#[template(path = "mobile/index.htm")]
+struct MobileTemplate {
+    bot_names: Vec<String>,
+    bot: Option<BotData>,
+}
+
+pub fn render(bot_names: Vec<String>, bot: Option<BotData>) -> Result<HttpResponse, Error> {
+    MobileTemplate { bot_names, bot }.into_response()
+}
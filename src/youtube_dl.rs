@@ -1,11 +1,19 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use std::process::Stdio;
 use tokio::process::Command;
 
 use serde::{Deserialize, Serialize};
 
-use slog::{debug, Logger};
+use slog::{debug, info, Logger};
+
+/// Name of the binary, yt-dlp can be used by linking it under this name
+const YOUTUBE_DL: &str = "youtube-dl";
+
+/// When the next request to a host is allowed, shared by all bots
+static NEXT_FETCH: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AudioMetadata {
@@ -16,8 +24,14 @@ pub struct AudioMetadata {
     pub thumbnail: Option<String>,
     #[serde(default, deserialize_with = "duration_deserialize")]
     pub duration: Option<Duration>,
-    #[serde(skip)]
+    /// Name of the user who queued the track, empty in youtube-dl output
+    #[serde(default)]
     pub added_by: String,
+    /// Only the part between the offsets is played if they are set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_offset: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_offset: Option<Duration>,
 }
 
 fn duration_deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
@@ -31,8 +45,13 @@ where
 
 pub async fn get_audio_download_from_url(
     url: String,
+    cooldown: Duration,
     logger: &Logger,
-) -> Result<AudioMetadata, String> {
+) -> Result<Vec<AudioMetadata>, String> {
+    if cooldown > Duration::from_secs(0) {
+        wait_for_cooldown(&url, cooldown, logger).await;
+    }
+
     //youtube-dl sometimes just fails, so we give it a second try
     let ytdl_output = match run_youtube_dl(&url, &logger).await {
         Ok(o) => o,
@@ -45,15 +64,93 @@ pub async fn get_audio_download_from_url(
         }
     };
 
-    let output = serde_json::from_str(&ytdl_output).map_err(|e| e.to_string())?;
+    parse_entries(&ytdl_output)
+}
+
+/// Some sites expand links to several entries despite `--no-playlist`,
+/// youtube-dl then prints one object per line or, in some versions, an array.
+/// At least one entry is returned.
+fn parse_entries(output: &str) -> Result<Vec<AudioMetadata>, String> {
+    let entries = if output.trim_start().starts_with('[') {
+        serde_json::from_str(output).map_err(|e| e.to_string())?
+    } else {
+        output
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?
+    };
+
+    if entries.is_empty() {
+        return Err(String::from("youtube-dl did not find anything"));
+    }
+
+    Ok(entries)
+}
+
+/// Waits until `cooldown` has passed since the last request to the host of `url`.
+async fn wait_for_cooldown(url: &str, cooldown: Duration, logger: &Logger) {
+    let host = match host_of(url) {
+        Some(host) => host,
+        None => return,
+    };
+
+    let now = Instant::now();
+    let start = {
+        let mut next_fetch = NEXT_FETCH.lock().unwrap();
+        let start = match next_fetch.get(&host) {
+            Some(&next) if next > now => next,
+            _ => now,
+        };
+        // Reserve the slot so that concurrent requests queue up behind this one
+        next_fetch.insert(host.clone(), start + cooldown);
 
-    Ok(output)
+        start
+    };
+
+    if start > now {
+        info!(logger, "Waiting for host cooldown"; "host" => &host, "delay" => ?(start - now));
+        tokio::time::sleep_until(start.into()).await;
+    }
+}
+
+/// Returns the host of urls like `https://host:port/path`, searches like
+/// `ytsearch:query` have none.
+pub fn host_of(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let host = rest.split(|c| c == '/' || c == '?' || c == '#').next()?;
+    // Strip credentials and port
+    let host = host.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+/// Version of the youtube-dl binary, fails if it can not be run.
+pub async fn version() -> Result<String, String> {
+    let output = Command::new(YOUTUBE_DL)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run {}: {}", YOUTUBE_DL, e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_owned());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
 }
 
 async fn run_youtube_dl(url: &str, logger: &Logger) -> Result<String, String> {
     let ytdl_args = ["--no-playlist", "-f", "bestaudio/best", "-j", &url];
 
-    let mut cmd = Command::new("youtube-dl");
+    let mut cmd = Command::new(YOUTUBE_DL);
     cmd.args(&ytdl_args);
     cmd.stdin(Stdio::null());
 
@@ -69,3 +166,46 @@ async fn run_youtube_dl(url: &str, logger: &Logger) -> Result<String, String> {
 
     Ok(output_str)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_and_multiple_entries() {
+        let single = r#"{"url": "https://a", "title": "A"}"#;
+        assert_eq!(parse_entries(single).unwrap().len(), 1);
+
+        let lines = "{\"url\": \"https://a\", \"title\": \"A\"}\n{\"url\": \"https://b\", \"title\": \"B\"}\n";
+        let entries = parse_entries(lines).unwrap();
+        assert_eq!(entries[1].title, "B");
+
+        let array = r#"[{"url": "https://a", "title": "A"}, {"url": "https://b", "title": "B"}]"#;
+        assert_eq!(parse_entries(array).unwrap().len(), 2);
+
+        assert!(parse_entries("").is_err());
+        assert!(parse_entries("[]").is_err());
+    }
+
+    #[test]
+    fn host_of_urls() {
+        assert_eq!(
+            host_of("https://www.YouTube.com/watch?v=a").as_deref(),
+            Some("www.youtube.com")
+        );
+        assert_eq!(
+            host_of("https://music.youtube.com/watch").as_deref(),
+            Some("music.youtube.com")
+        );
+        assert_eq!(
+            host_of("http://user:pw@example.com:8080/a").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            host_of("https://example.com#top").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(host_of("file:///music/song.mp3"), None);
+        assert_eq!(host_of("ytsearch:some song"), None);
+    }
+}